@@ -0,0 +1,6843 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use chrono_tz::Tz;
+use mail_parser::{Address, MessageParser, MimeHeaders};
+use smtp_proto::Request;
+use smtp_proto::Response;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
+use tokio::net::TcpStream;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Instrument};
+
+use ammonia::{Builder, UrlRelative};
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+static TELEGRAM_HTML_SANITIZER: Lazy<Builder<'static>> = Lazy::new(|| {
+    // Allowed tags according to Telegram HTML-style
+    let mut tags: HashSet<&'static str> = HashSet::new();
+    for t in [
+        "b",
+        "strong",
+        "i",
+        "em",
+        "u",
+        "ins",
+        "s",
+        "strike",
+        "del",
+        "span",
+        "tg-spoiler",
+        "a",
+        "code",
+        "pre",
+    ] {
+        tags.insert(t);
+    }
+
+    // Build tag attributes map
+    let mut tag_attrs: HashMap<&'static str, HashSet<&'static str>> = HashMap::new();
+    let mut a_attrs = HashSet::new();
+    a_attrs.insert("href");
+    tag_attrs.insert("a", a_attrs);
+
+    let mut span_attrs = HashSet::new();
+    span_attrs.insert("class");
+    tag_attrs.insert("span", span_attrs);
+
+    let mut code_attrs = HashSet::new();
+    code_attrs.insert("class");
+    tag_attrs.insert("code", code_attrs);
+
+    let mut pre_attrs = HashSet::new();
+    pre_attrs.insert("class");
+    tag_attrs.insert("pre", pre_attrs);
+
+    // Builder for sanitizer
+    let mut builder = Builder::default();
+
+    builder
+        // Allow only listed tags
+        .tags(tags)
+        // By default ammonia allows many attributes,
+        // here we reset everything and explicitly set what we need
+        .generic_attributes(HashSet::new())
+        .tag_attributes(tag_attrs)
+        // Don't touch relative URLs (Telegram will handle them)
+        .url_relative(UrlRelative::PassThrough)
+        // Don't add/change rel on links
+        .link_rel(None)
+        // Filter attribute values (keep only allowed ones)
+        .attribute_filter(|tag, attr, value| {
+            match (tag, attr) {
+                // Allow only class="tg-spoiler" on <span>
+                ("span", "class") => {
+                    let has_spoiler = value.split_whitespace().any(|cls| cls == "tg-spoiler");
+                    if has_spoiler {
+                        // Telegram only needs one tg-spoiler class
+                        Some(Cow::Borrowed("tg-spoiler"))
+                    } else {
+                        None
+                    }
+                }
+                // On <code>/<pre> keep only class="language-..."
+                ("code", "class") | ("pre", "class") => {
+                    if value.starts_with("language-") {
+                        Some(Cow::Borrowed(value))
+                    } else {
+                        None
+                    }
+                }
+                // Other allowed attributes keep as is
+                _ => Some(Cow::Borrowed(value)),
+            }
+        });
+
+    builder
+});
+
+// HTML tag found while preprocessing: lowercase name, closing flag and the raw source text
+struct HtmlTag<'a> {
+    name: String,
+    closing: bool,
+    raw: &'a str,
+}
+
+// Parses a tag (or comment) at the start of `input`, which must begin with '<'
+fn parse_html_tag(input: &str) -> Option<HtmlTag<'_>> {
+    if let Some(comment) = input.strip_prefix("<!--") {
+        let end = comment
+            .find("-->")
+            .map(|pos| 4 + pos + 3)
+            .unwrap_or(input.len());
+        return Some(HtmlTag {
+            name: "!--".to_string(),
+            closing: false,
+            raw: &input[..end],
+        });
+    }
+
+    let bytes = input.as_bytes();
+    let closing = bytes.get(1) == Some(&b'/');
+    let name_start = if closing { 2 } else { 1 };
+    if !bytes
+        .get(name_start)
+        .is_some_and(|b| b.is_ascii_alphabetic() || *b == b'!')
+    {
+        return None;
+    }
+
+    // Find the closing '>' while skipping quoted attribute values
+    let mut quote: Option<u8> = None;
+    let mut end = None;
+    for (i, &b) in bytes.iter().enumerate().skip(name_start) {
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => {}
+            None if b == b'"' || b == b'\'' => quote = Some(b),
+            None if b == b'>' => {
+                end = Some(i + 1);
+                break;
+            }
+            None => {}
+        }
+    }
+    let end = end?;
+
+    let name: String = input[name_start..end]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '!')
+        .collect();
+
+    Some(HtmlTag {
+        name: name.to_ascii_lowercase(),
+        closing,
+        raw: &input[..end],
+    })
+}
+
+fn is_html_heading(name: &str) -> bool {
+    matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+}
+
+// Whitespace inside these tags is content and must be kept as is
+fn is_html_preformatted(name: &str) -> bool {
+    matches!(name, "pre" | "code")
+}
+
+// Follows <pre>/<code> nesting through one line of HTML, starting at `depth`
+fn preformatted_depth_after(line: &str, mut depth: usize) -> usize {
+    let mut rest = line;
+    while let Some(pos) = rest.find('<') {
+        let tail = &rest[pos..];
+        match parse_html_tag(tail) {
+            Some(tag) => {
+                if is_html_preformatted(&tag.name) {
+                    depth = if tag.closing {
+                        depth.saturating_sub(1)
+                    } else {
+                        depth + 1
+                    };
+                }
+                rest = &tail[tag.raw.len()..];
+            }
+            None => rest = &tail[1..],
+        }
+    }
+    depth
+}
+
+// Trims lines and drops blank ones. In HTML, lines that start inside a <pre>/<code>
+// region are left untouched so code keeps its indentation and blank lines.
+fn clean_body_whitespace(body: &str, is_html: bool) -> String {
+    let mut depth = 0;
+    let mut lines = Vec::new();
+    for line in body.lines() {
+        let preformatted = depth > 0;
+        if is_html {
+            depth = preformatted_depth_after(line, depth);
+        }
+        if preformatted {
+            lines.push(line);
+        } else if !line.trim().is_empty() {
+            lines.push(line.trim());
+        }
+    }
+    lines.join("\n")
+}
+
+// Drops quoted reply lines ("> ...") and their "On ... wrote:" attribution
+fn trim_quoted_lines(body: &str) -> String {
+    let is_quote = |line: &str| line.trim_start().starts_with('>');
+    let lines: Vec<&str> = body.lines().collect();
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(index, line)| {
+            let attribution = line.trim_end().ends_with("wrote:")
+                && lines[index + 1..]
+                    .iter()
+                    .find(|next| !next.trim().is_empty())
+                    .is_some_and(|next| is_quote(next));
+            !is_quote(line) && !attribution
+        })
+        .map(|(_, line)| *line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Joins the soft line breaks of format=flowed text (RFC 3676): a line ending in a space
+// continues on the next line of the same quote depth. Space-stuffing is removed, quoted
+// lines keep a "> " prefix and the "-- " signature delimiter always stands alone.
+fn unflow_text(text: &str, delete_space: bool) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    // Quote depth of the previous line when it ended with a soft break
+    let mut continued = None;
+    for line in text.lines() {
+        let depth = line.chars().take_while(|&c| c == '>').count();
+        let content = &line[depth..];
+        let content = content.strip_prefix(' ').unwrap_or(content);
+        let signature = content == "-- ";
+        let soft = content.ends_with(' ') && !signature;
+        let content = match content.strip_suffix(' ') {
+            Some(stripped) if soft && delete_space => stripped,
+            _ => content,
+        };
+
+        match lines.last_mut() {
+            Some(last) if continued == Some(depth) && !signature => last.push_str(content),
+            _ if depth > 0 => lines.push(format!("{} {}", ">".repeat(depth), content)),
+            _ => lines.push(content.to_string()),
+        }
+        continued = soft.then_some(depth);
+    }
+    lines.join("\n")
+}
+
+// Drops quoted replies and everything from the "-- " signature delimiter on, for
+// --strip-quotes. A message that is nothing but quotes is kept as is.
+fn strip_quotes(body: &str) -> String {
+    let without_signature = match body.lines().position(|line| line == "-- ") {
+        Some(delimiter) => body.lines().take(delimiter).collect::<Vec<_>>().join("\n"),
+        None => body.to_string(),
+    };
+    let stripped = trim_quoted_lines(&without_signature);
+    if stripped.trim().is_empty() {
+        return body.to_string();
+    }
+    stripped
+}
+
+// Rewrites block-level HTML structure that Telegram doesn't support into plain-text equivalents
+struct HtmlBlockRewriter {
+    out: String,
+    // Line breaks the next piece of text must be separated by
+    pending_breaks: usize,
+    // Open lists: None for <ul>, Some(counter) for <ol>
+    lists: Vec<Option<usize>>,
+    // Nesting of <pre>/<code>, where text is passed through verbatim
+    preformatted: usize,
+}
+
+impl HtmlBlockRewriter {
+    fn new(capacity: usize) -> Self {
+        Self {
+            out: String::with_capacity(capacity),
+            pending_breaks: 0,
+            lists: Vec::new(),
+            preformatted: 0,
+        }
+    }
+
+    fn trailing_breaks(&self) -> usize {
+        self.out
+            .trim_end_matches([' ', '\t'])
+            .chars()
+            .rev()
+            .take_while(|c| *c == '\n')
+            .count()
+    }
+
+    // Drops spaces left at the end of a line of prose
+    fn trim_line_end(&mut self) {
+        if self.preformatted == 0 {
+            let len = self.out.trim_end_matches([' ', '\t']).len();
+            self.out.truncate(len);
+        }
+    }
+
+    // Makes sure the output ends with at least `count` line breaks (nothing at the very start)
+    fn ensure_breaks(&mut self, count: usize) {
+        if self.out.trim().is_empty() {
+            return;
+        }
+        if count > 0 {
+            self.trim_line_end();
+        }
+        for _ in self.trailing_breaks()..count {
+            self.out.push('\n');
+        }
+    }
+
+    // Starts a block on a new line, honoring breaks still pending from the previous block
+    fn start_block(&mut self, breaks: usize) {
+        let breaks = std::mem::take(&mut self.pending_breaks).max(breaks);
+        self.ensure_breaks(breaks);
+    }
+
+    fn text(&mut self, mut text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if self.preformatted > 0 {
+            let breaks = std::mem::take(&mut self.pending_breaks);
+            self.ensure_breaks(breaks);
+            self.out.push_str(text);
+            return;
+        }
+        // Whitespace between list tags is source formatting, not content
+        if !self.lists.is_empty() && text.trim().is_empty() {
+            return;
+        }
+        if self.pending_breaks > 0 {
+            // Breaks after a block come from the block itself, not from source formatting
+            let trimmed = text.trim_start();
+            if trimmed.is_empty() {
+                return;
+            }
+            let breaks = std::mem::take(&mut self.pending_breaks);
+            self.ensure_breaks(breaks);
+            text = trimmed;
+        }
+        // Prose whitespace collapses like in a browser, and lines don't start with it
+        let at_line_start = self.out.is_empty() || self.out.ends_with('\n');
+        let mut last_blank = at_line_start;
+        for c in text.chars() {
+            let blank = c == ' ' || c == '\t';
+            if !(blank && last_blank) {
+                self.out.push(if blank { ' ' } else { c });
+            }
+            last_blank = blank || c == '\n';
+        }
+    }
+
+    fn tag(&mut self, tag: &HtmlTag) {
+        match (tag.name.as_str(), tag.closing) {
+            ("ul", false) => {
+                self.start_block(1);
+                self.lists.push(None);
+            }
+            ("ol", false) => {
+                self.start_block(1);
+                self.lists.push(Some(0));
+            }
+            ("ul", true) | ("ol", true) => {
+                self.lists.pop();
+                self.pending_breaks = self.pending_breaks.max(1);
+            }
+            ("li", false) => {
+                self.start_block(1);
+                let depth = self.lists.len().saturating_sub(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(counter)) => {
+                        *counter += 1;
+                        format!("{}. ", counter)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.out.push_str(&"  ".repeat(depth));
+                self.out.push_str(&marker);
+            }
+            ("li", true) => {}
+            // Code blocks start on their own line, their content is kept verbatim
+            ("pre", false) => {
+                self.start_block(1);
+                self.out.push_str(tag.raw);
+                self.preformatted += 1;
+            }
+            ("pre", true) => {
+                self.out.push_str(tag.raw);
+                self.preformatted = self.preformatted.saturating_sub(1);
+                self.pending_breaks = self.pending_breaks.max(1);
+            }
+            ("code", closing) => {
+                self.out.push_str(tag.raw);
+                if closing {
+                    self.preformatted = self.preformatted.saturating_sub(1);
+                } else {
+                    self.preformatted += 1;
+                }
+            }
+            // Paragraphs are separated by a blank line, <br> is a single line break
+            ("p", false) => self.start_block(2),
+            ("p", true) => self.pending_breaks = self.pending_breaks.max(2),
+            ("br", _) => self.pending_breaks = (self.pending_breaks + 1).min(2),
+            // Headings become bold lines of their own (<b> is allowed by Telegram)
+            (name, false) if is_html_heading(name) => {
+                self.start_block(1);
+                self.out.push_str("<b>");
+            }
+            (name, true) if is_html_heading(name) => {
+                self.out.push_str("</b>");
+                self.pending_breaks = self.pending_breaks.max(1);
+            }
+            _ => self.out.push_str(tag.raw),
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.trim_line_end();
+        self.out
+    }
+}
+
+// Telegram HTML has no block-level tags, so the sanitizer would flatten lists and
+// similar structure into run-on text. Rewrite them into text equivalents first.
+fn preprocess_html_blocks(html: &str) -> String {
+    let mut rewriter = HtmlBlockRewriter::new(html.len());
+    let mut rest = html;
+
+    while let Some(pos) = rest.find('<') {
+        let (text, tail) = rest.split_at(pos);
+        rewriter.text(text);
+        match parse_html_tag(tail) {
+            Some(tag) => {
+                rest = &tail[tag.raw.len()..];
+                rewriter.tag(&tag);
+            }
+            None => {
+                // Not a tag - keep the '<' as text for the sanitizer to escape
+                rewriter.text("<");
+                rest = &tail[1..];
+            }
+        }
+    }
+    rewriter.text(rest);
+
+    rewriter.finish()
+}
+
+// Piece of Telegram HTML: a tag, or one visible character (a char or an entity)
+enum HtmlToken<'a> {
+    Tag(HtmlTag<'a>),
+    Text(&'a str),
+}
+
+fn tokenize_html(html: &str) -> Vec<HtmlToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '<' => match parse_html_tag(rest) {
+                Some(tag) => {
+                    let len = tag.raw.len();
+                    tokens.push(HtmlToken::Tag(tag));
+                    rest = &rest[len..];
+                    continue;
+                }
+                None => 1,
+            },
+            // Entities like &amp; or &#39; are a single character after parsing
+            '&' => rest
+                .find(';')
+                .filter(|&end| {
+                    end <= 10
+                        && rest[1..end]
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '#')
+                })
+                .map(|end| end + 1)
+                .unwrap_or(1),
+            _ => c.len_utf8(),
+        };
+        tokens.push(HtmlToken::Text(&rest[..len]));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+// Text of an HTML text token with its entity decoded
+fn decode_html_token(text: &str) -> Cow<'_, str> {
+    match text {
+        "&lt;" => Cow::Borrowed("<"),
+        "&gt;" => Cow::Borrowed(">"),
+        "&amp;" => Cow::Borrowed("&"),
+        "&quot;" => Cow::Borrowed("\""),
+        "&apos;" => Cow::Borrowed("'"),
+        _ => text
+            .strip_prefix("&#")
+            .and_then(|code| code.strip_suffix(';'))
+            .and_then(|code| match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => code.parse().ok(),
+            })
+            .and_then(char::from_u32)
+            .map(|c| Cow::Owned(c.to_string()))
+            .unwrap_or(Cow::Borrowed(text)),
+    }
+}
+
+// Length Telegram counts for an HTML text token, in UTF-16 code units
+fn html_token_len(text: &str) -> usize {
+    telegram_len(&decode_html_token(text))
+}
+
+// Length Telegram counts for a message: the text without markup, in UTF-16 code units
+fn html_visible_len(html: &str) -> usize {
+    tokenize_html(html)
+        .iter()
+        .map(|token| match token {
+            HtmlToken::Text(text) => html_token_len(text),
+            HtmlToken::Tag(_) => 0,
+        })
+        .sum()
+}
+
+// Text of Telegram HTML without tags, with entities decoded
+fn html_to_plain_text(html: &str) -> String {
+    tokenize_html(html)
+        .into_iter()
+        .filter_map(|token| match token {
+            HtmlToken::Text(text) => Some(decode_html_token(text)),
+            HtmlToken::Tag(_) => None,
+        })
+        .collect()
+}
+
+// Whether HTML shows any text, as opposed to only markup and whitespace
+fn html_has_text(html: &str) -> bool {
+    !html_to_plain_text(html).trim().is_empty()
+}
+
+// Splits Telegram HTML into chunks of at most `max_len` visible UTF-16 code units, as
+// Telegram counts them. Breaks only fall between tags and entities, preferring a blank
+// line between paragraphs, then a line break, then a space, within the last `lookback`
+// code units. Tags open at a break are
+// closed at the end of the chunk and reopened at the start of the next, so every chunk is
+// valid on its own.
+pub fn split_html_chunks(html: &str, max_len: usize, lookback: usize) -> Vec<String> {
+    let tokens = tokenize_html(html);
+    let mut chunks = Vec::new();
+    // Tags open where the current chunk starts
+    let mut open_at_start: Vec<&HtmlTag> = Vec::new();
+    let mut start = 0;
+
+    while start < tokens.len() {
+        let mut open = open_at_start.clone();
+        let mut visible = 0;
+        // Token index after a break, the tags open there and the visible length before it
+        let mut paragraph_break = None;
+        let mut line_break = None;
+        let mut space_break = None;
+        // Whether the current line is blank so far and follows a line break
+        let mut blank_line = false;
+        let mut cut = None;
+
+        for (i, token) in tokens.iter().enumerate().skip(start) {
+            match token {
+                HtmlToken::Tag(tag) if tag.closing => {
+                    if let Some(pos) = open.iter().rposition(|t| t.name == tag.name) {
+                        open.remove(pos);
+                    }
+                }
+                HtmlToken::Tag(tag) => open.push(tag),
+                HtmlToken::Text(text) => {
+                    let width = html_token_len(text);
+                    if visible > 0 && visible + width > max_len {
+                        let min_visible = max_len.saturating_sub(lookback);
+                        cut = [
+                            paragraph_break.take(),
+                            line_break.take(),
+                            space_break.take(),
+                        ]
+                        .into_iter()
+                        .flatten()
+                        .find(|(_, _, before)| *before >= min_visible)
+                        .or(Some((i, open.clone(), visible)));
+                        break;
+                    }
+                    visible += width;
+                    if *text == "\n" {
+                        if blank_line {
+                            paragraph_break = Some((i + 1, open.clone(), visible));
+                        }
+                        line_break = Some((i + 1, open.clone(), visible));
+                        blank_line = true;
+                    } else if text.trim().is_empty() {
+                        space_break = Some((i + 1, open.clone(), visible));
+                    } else {
+                        blank_line = false;
+                    }
+                }
+            }
+        }
+
+        let (end, open_at_end) = match cut {
+            Some((end, open_at_end, _)) => (end, open_at_end),
+            None => (tokens.len(), Vec::new()),
+        };
+
+        let mut chunk: String = open_at_start.iter().map(|tag| tag.raw).collect();
+        for token in &tokens[start..end] {
+            chunk.push_str(match token {
+                HtmlToken::Tag(tag) => tag.raw,
+                HtmlToken::Text(text) => text,
+            });
+        }
+        for tag in open_at_end.iter().rev() {
+            chunk.push_str(&format!("</{}>", tag.name));
+        }
+        chunks.push(chunk);
+
+        start = end;
+        open_at_start = open_at_end;
+    }
+
+    chunks
+}
+
+// Length of text as Telegram counts it, in UTF-16 code units
+fn telegram_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+// Length Telegram counts for a message or caption sent with the parse mode. The limit applies
+// to the text after entities parsing, so HTML markup doesn't count.
+fn message_len(text: &str, parse_mode: Option<&str>) -> usize {
+    if parse_mode == Some("HTML") {
+        html_visible_len(text)
+    } else {
+        telegram_len(text)
+    }
+}
+
+// How far back from the length limit the splitters look for a paragraph, line or word
+// break, in characters
+pub const DEFAULT_SPLIT_LOOKBACK: usize = 1000;
+
+// Splits plain text into chunks of at most `max` characters as Telegram counts them, see
+// split_message_with_lookback
+pub fn split_message(text: &str, max: usize) -> Vec<String> {
+    split_message_with_lookback(text, max, DEFAULT_SPLIT_LOOKBACK)
+}
+
+// Splits plain text into chunks of at most `max` characters as Telegram counts them,
+// preferring a blank line between paragraphs, then a line break, then a space, within the
+// last `lookback` characters. Chunks end on grapheme cluster boundaries, so emoji sequences
+// and combining characters stay whole, and never in the middle of a backslash escape, so
+// Markdown stays valid across the break.
+pub fn split_message_with_lookback(text: &str, max: usize, lookback: usize) -> Vec<String> {
+    let max = max.max(1);
+    let mut chunks = Vec::new();
+    let mut remaining = text;
+
+    while !remaining.is_empty() {
+        // Byte positions of the length limit and of the start of the break window
+        let mut length = 0;
+        let mut limit = None;
+        let mut window_start = 0;
+        for (pos, grapheme) in remaining.grapheme_indices(true) {
+            if length <= max.saturating_sub(lookback) {
+                window_start = pos;
+            }
+            length += telegram_len(grapheme);
+            if length > max {
+                // A single cluster over the limit still has to go somewhere
+                limit = Some(if pos == 0 { grapheme.len() } else { pos });
+                break;
+            }
+        }
+        let Some(limit) = limit else {
+            chunks.push(remaining.to_string());
+            break;
+        };
+
+        // Last break of each kind in the window, as the byte position after it
+        let (mut paragraph_break, mut line_break, mut space_break) = (None, None, None);
+        // Whether the current line is blank so far and follows a line break
+        let mut blank_line = false;
+        for (pos, grapheme) in remaining[window_start..limit].grapheme_indices(true) {
+            let end = window_start + pos + grapheme.len();
+            if grapheme.contains('\n') {
+                if blank_line {
+                    paragraph_break = Some(end);
+                }
+                line_break = Some(end);
+                blank_line = true;
+            } else if grapheme.trim().is_empty() {
+                space_break = Some(end);
+            } else {
+                blank_line = false;
+            }
+        }
+        let mut split_pos = paragraph_break
+            .or(line_break)
+            .or(space_break)
+            .unwrap_or(limit);
+
+        let backslashes = remaining[..split_pos]
+            .bytes()
+            .rev()
+            .take_while(|&b| b == b'\\')
+            .count();
+        if backslashes % 2 == 1 && split_pos > 1 {
+            split_pos -= 1;
+        }
+
+        let (chunk, rest) = remaining.split_at(split_pos);
+        chunks.push(chunk.to_string());
+        remaining = rest;
+    }
+
+    chunks
+}
+
+// Splits a message into parts that each fit in `max` characters together with their
+// prefix, prefix(number, total). Parts leave room for the widest prefix of the part
+// count, and the text is split again when the count needs another digit. A message that
+// ends up in one part gets no prefix.
+pub fn split_with_prefix(
+    text: &str,
+    max: usize,
+    split: impl Fn(&str, usize) -> Vec<String>,
+    prefix: impl Fn(usize, usize) -> String,
+) -> Vec<String> {
+    let mut widest = 999;
+    loop {
+        let prefix_len = telegram_len(&prefix(widest, widest));
+        let chunks = split(text, max.saturating_sub(prefix_len));
+        if chunks.len() == 1 {
+            return chunks;
+        }
+        if chunks.len() <= widest {
+            let total = chunks.len();
+            return chunks
+                .into_iter()
+                .enumerate()
+                .map(|(index, chunk)| format!("{}{}", prefix(index + 1, total), chunk))
+                .collect();
+        }
+        widest = widest * 10 + 9;
+    }
+}
+
+// Telegram parse mode used for plain-text emails
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ParseMode {
+    Html,
+    Markdownv2,
+    None,
+}
+
+impl ParseMode {
+    // Value of the Bot API parse_mode parameter
+    fn api_name(self) -> Option<&'static str> {
+        match self {
+            ParseMode::Html => Some("HTML"),
+            ParseMode::Markdownv2 => Some("MarkdownV2"),
+            ParseMode::None => None,
+        }
+    }
+}
+
+// Part of multipart emails that is forwarded when both exist
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PreferredPart {
+    Html,
+    Text,
+}
+
+// Emails --attach-eml uploads the raw message for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AttachEml {
+    /// Every email, alongside its text
+    Always,
+    /// Only emails without any text
+    Empty,
+}
+
+// Step of the body transformation pipeline, applied in the order given on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BodyTransform {
+    /// Drop quoted reply lines ("> ...") and their "On ... wrote:" attribution
+    TrimQuotes,
+    /// Replace email addresses and card-like numbers with [redacted]
+    Redact,
+    /// Apply NFKC normalization and remove zero-width characters
+    NormalizeUnicode,
+    /// Collapse runs of spaces and tabs, and consecutive blank lines
+    CollapseWhitespace,
+    /// Remove http(s) links
+    StripUrls,
+}
+
+static EMAIL_ADDRESS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap());
+static CARD_NUMBER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap());
+static URL_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"https?://[^\s<>"']+"#).unwrap());
+
+impl BodyTransform {
+    fn apply(self, body: &str) -> String {
+        match self {
+            BodyTransform::TrimQuotes => trim_quoted_lines(body),
+            BodyTransform::Redact => {
+                let body = EMAIL_ADDRESS_RE.replace_all(body, "[redacted]");
+                CARD_NUMBER_RE.replace_all(&body, "[redacted]").into_owned()
+            }
+            BodyTransform::NormalizeUnicode => body
+                .nfkc()
+                .filter(|c| {
+                    !matches!(
+                        c,
+                        '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{AD}'
+                    )
+                })
+                .collect(),
+            BodyTransform::CollapseWhitespace => {
+                let mut lines: Vec<String> = Vec::new();
+                for line in body.lines() {
+                    let line = line
+                        .split([' ', '\t'])
+                        .filter(|w| !w.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    if line.is_empty() && lines.last().is_some_and(|last| last.is_empty()) {
+                        continue;
+                    }
+                    lines.push(line);
+                }
+                lines.join("\n")
+            }
+            BodyTransform::StripUrls => URL_RE.replace_all(body, "").into_owned(),
+        }
+    }
+}
+
+// Runs the body through the configured transforms in order
+fn apply_transforms(body: String, transforms: &[BodyTransform]) -> String {
+    transforms
+        .iter()
+        .fold(body, |body, transform| transform.apply(&body))
+}
+
+// Authentication methods shown in the summary line, in display order
+const AUTH_RESULT_METHODS: &[&str] = &["spf", "dkim", "dmarc", "arc"];
+
+// Summarizes Authentication-Results (RFC 8601) and Received-SPF headers into a line
+// like "spf=pass dkim=fail dmarc=pass". The first result found for a method wins, so
+// headers should be passed top to bottom (closest MTA first).
+fn summarize_auth_results(auth_results: &[String], received_spf: &[String]) -> Option<String> {
+    let mut results: Vec<(&str, String)> = Vec::new();
+
+    for header in auth_results {
+        // The first segment is the authserv-id, each following one a "method=result ..." entry
+        for segment in header.split(';').skip(1) {
+            let Some((method, result)) = segment.trim().split_once('=') else {
+                continue;
+            };
+            let method = method.trim().to_ascii_lowercase();
+            let Some(method) = AUTH_RESULT_METHODS.iter().find(|m| **m == method) else {
+                continue;
+            };
+            let result: String = result
+                .trim_start()
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            if !result.is_empty() && !results.iter().any(|(m, _)| m == method) {
+                results.push((method, result.to_ascii_lowercase()));
+            }
+        }
+    }
+
+    // Received-SPF starts with the result, e.g. "Pass (sender SPF authorized) ..."
+    if !results.iter().any(|(m, _)| *m == "spf") {
+        if let Some(result) = received_spf
+            .iter()
+            .filter_map(|header| header.split_whitespace().next())
+            .next()
+        {
+            results.push(("spf", result.to_ascii_lowercase()));
+        }
+    }
+
+    if results.is_empty() {
+        return None;
+    }
+
+    results.sort_by_key(|(method, _)| AUTH_RESULT_METHODS.iter().position(|m| m == method));
+    Some(
+        results
+            .iter()
+            .map(|(method, result)| format!("{}={}", method, result))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+// Reads a bot token from a file, ignoring surrounding whitespace
+pub fn read_token_file(path: &Path) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read token file {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow::anyhow!("Token file {} is empty", path.display()));
+    }
+    Ok(token.to_string())
+}
+
+// Name of this machine for the SMTP greeting, falling back to "localhost"
+pub fn system_hostname() -> String {
+    #[cfg(unix)]
+    let hostname = nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok());
+    #[cfg(not(unix))]
+    let hostname = std::env::var("COMPUTERNAME").ok();
+    hostname
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+// Shows only the ends of the bot token in logs, never the secret part
+pub fn mask_token(token: &str) -> String {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() < 12 {
+        return "***".to_string();
+    }
+    let head: String = chars[..3].iter().collect();
+    let tail: String = chars[chars.len() - 3..].iter().collect();
+    format!("{}***{}", head, tail)
+}
+
+// "#1a2b3c Subject" shown on every part of a split message with --chunk-header. The id is
+// a hash of the Message-ID (or of the raw message), long subjects are shortened.
+fn chunk_label(subject: &str, id_source: &[u8]) -> String {
+    const MAX_SUBJECT_LENGTH: usize = 64;
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    id_source.hash(&mut hasher);
+    let id = format!("#{:06x}", hasher.finish() & 0xff_ffff);
+
+    let subject = if subject.chars().count() > MAX_SUBJECT_LENGTH {
+        let short: String = subject.chars().take(MAX_SUBJECT_LENGTH - 1).collect();
+        format!("{}…", short.trim_end())
+    } else {
+        subject.to_string()
+    };
+    format!("{} {}", id, subject).trim_end().to_string()
+}
+
+// Removes control characters except line breaks and tabs
+fn strip_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|&c| !c.is_control() || c == '\n' || c == '\t')
+        .collect()
+}
+
+// Comma-separated "Name <address>" entries of an address header
+fn format_address_list(address: &Address) -> String {
+    address
+        .iter()
+        .filter_map(|addr| match (&addr.name, &addr.address) {
+            (Some(name), Some(address)) => Some(format!("{} <{}>", name, address)),
+            (None, Some(address)) => Some(address.to_string()),
+            (Some(name), None) => Some(name.to_string()),
+            (None, None) => None,
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Extracts the links from a List-Unsubscribe header (RFC 2369): a comma-separated
+// list of <URI>, where only https/http and mailto are useful to a reader. Https
+// links come first since they work without an email client.
+fn parse_list_unsubscribe(value: &str) -> Vec<String> {
+    let mut links: Vec<String> = value
+        .split(',')
+        .filter_map(|entry| {
+            let uri = entry.trim().strip_prefix('<')?.split('>').next()?.trim();
+            let scheme = uri.split(':').next()?.to_ascii_lowercase();
+            matches!(scheme.as_str(), "https" | "http" | "mailto").then(|| uri.to_string())
+        })
+        .collect();
+    links.sort_by_key(|uri| !uri.to_ascii_lowercase().starts_with("http"));
+    links
+}
+
+// Checks a chat ID is a number, negative for groups and channels, or a public @username
+pub fn parse_chat_id(value: &str) -> Result<String, String> {
+    let chat_id = value.trim();
+    if chat_id.is_empty() {
+        return Err("the chat ID is empty".to_string());
+    }
+    let valid = match chat_id.strip_prefix('@') {
+        // Usernames are 5 to 32 letters, digits and underscores, starting with a letter
+        Some(username) => {
+            (5..=32).contains(&username.len())
+                && username.starts_with(|c: char| c.is_ascii_alphabetic())
+                && username
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => chat_id.parse::<i64>().is_ok_and(|id| id != 0),
+    };
+    if !valid {
+        return Err(format!(
+            "invalid chat ID '{}', expected a number like -1001234567890 or a @username",
+            chat_id
+        ));
+    }
+    Ok(chat_id.to_string())
+}
+
+// Recipient pattern mapped to a destination chat, and optionally a forum topic in it
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub pattern: String,
+    pub chat_id: String,
+    pub thread_id: Option<i64>,
+}
+
+pub fn parse_route(value: &str) -> Result<Route, String> {
+    let (pattern, destination) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATTERN=CHAT_ID[:THREAD_ID], got '{}'", value))?;
+    let (chat_id, thread_id) = match destination.split_once(':') {
+        Some((chat_id, thread_id)) => {
+            let thread_id = thread_id
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid thread id in '{}'", value))?;
+            (chat_id, Some(thread_id))
+        }
+        None => (destination, None),
+    };
+    let (pattern, chat_id) = (pattern.trim(), chat_id.trim());
+    if pattern.is_empty() || chat_id.is_empty() {
+        return Err(format!(
+            "expected PATTERN=CHAT_ID[:THREAD_ID], got '{}'",
+            value
+        ));
+    }
+    Ok(Route {
+        pattern: pattern.to_lowercase(),
+        chat_id: parse_chat_id(chat_id)?,
+        thread_id,
+    })
+}
+
+// Sender pattern mapped to a forum topic (message_thread_id)
+#[derive(Clone, Debug)]
+pub struct SenderTopic {
+    pub pattern: String,
+    pub thread_id: i64,
+}
+
+pub fn parse_sender_topic(value: &str) -> Result<SenderTopic, String> {
+    let (pattern, thread_id) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATTERN=THREAD_ID, got '{}'", value))?;
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return Err(format!("expected PATTERN=THREAD_ID, got '{}'", value));
+    }
+    let thread_id = thread_id
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid thread id in '{}'", value))?;
+    Ok(SenderTopic {
+        pattern: pattern.to_lowercase(),
+        thread_id,
+    })
+}
+
+// Inline keyboard button whose URL is a template filled in per message
+#[derive(Clone, Debug)]
+pub struct Button {
+    pub label: String,
+    pub url: String,
+}
+
+pub fn parse_button(value: &str) -> Result<Button, String> {
+    let (label, url) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected LABEL=URL, got '{}'", value))?;
+    let (label, url) = (label.trim(), url.trim());
+    if label.is_empty() {
+        return Err(format!("expected LABEL=URL, got '{}'", value));
+    }
+    // Telegram only accepts these schemes for URL buttons
+    if !["http://", "https://", "tg://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+    {
+        return Err(format!(
+            "button URL must start with http://, https:// or tg://, got '{}'",
+            url
+        ));
+    }
+    Ok(Button {
+        label: label.to_string(),
+        url: url.to_string(),
+    })
+}
+
+// IPv4 or IPv6 network clients may connect from
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    pub network: std::net::IpAddr,
+    pub prefix_len: u32,
+}
+
+impl Cidr {
+    // True when no networks are configured
+    pub fn allows(cidrs: &[Cidr], addr: std::net::IpAddr) -> bool {
+        cidrs.is_empty() || cidrs.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    fn contains(&self, addr: std::net::IpAddr) -> bool {
+        // Clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.network, addr.to_canonical()) {
+            (std::net::IpAddr::V4(network), std::net::IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (std::net::IpAddr::V6(network), std::net::IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Parses `ADDRESS/PREFIX`, or a single address
+pub fn parse_cidr(value: &str) -> Result<Cidr, String> {
+    let value = value.trim();
+    let (address, prefix_len) = match value.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len)),
+        None => (value, None),
+    };
+    let network: std::net::IpAddr = address
+        .parse()
+        .map_err(|_| format!("invalid IP address in '{}'", value))?;
+    let max_len = if network.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => prefix_len
+            .parse()
+            .ok()
+            .filter(|len| *len <= max_len)
+            .ok_or_else(|| format!("invalid prefix length in '{}'", value))?,
+        None => max_len,
+    };
+    // Peers are compared in canonical form, so an IPv4-mapped range becomes an IPv4 one
+    if let std::net::IpAddr::V6(v6) = network {
+        if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix_len >= 96) {
+            return Ok(Cidr {
+                network: v4.into(),
+                prefix_len: prefix_len - 96,
+            });
+        }
+    }
+    Ok(Cidr {
+        network,
+        prefix_len,
+    })
+}
+
+// Signature that opens a PROXY protocol v2 header
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// Reads the PROXY protocol header (v1 or v2) a load balancer sends ahead of the client's
+// traffic. Returns the original client address, or None for health checks (v2 LOCAL, v1
+// UNKNOWN) and address families other than TCP over IPv4/IPv6.
+pub async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<std::net::SocketAddr>> {
+    // The shortest v1 header, "PROXY UNKNOWN\r\n", is longer than the v2 signature
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == PROXY_V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        if fixed[0] >> 4 != 2 {
+            anyhow::bail!("Unsupported PROXY protocol version {}", fixed[0] >> 4);
+        }
+        let mut addresses = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        if fixed[0] & 0x0f == 0 {
+            return Ok(None);
+        }
+        let address = match fixed[1] {
+            0x11 if addresses.len() >= 12 => {
+                let ip: [u8; 4] = addresses[0..4].try_into()?;
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Some(std::net::SocketAddr::from((ip, port)))
+            }
+            0x21 if addresses.len() >= 36 => {
+                let ip: [u8; 16] = addresses[0..16].try_into()?;
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Some(std::net::SocketAddr::from((ip, port)))
+            }
+            0x11 | 0x21 => anyhow::bail!("Truncated PROXY protocol v2 address block"),
+            _ => None,
+        };
+        return Ok(address);
+    }
+
+    if !prefix.starts_with(b"PROXY ") {
+        anyhow::bail!("Connection did not start with a PROXY protocol header");
+    }
+    // A v1 header is a single line of at most 107 bytes, read bytewise so no SMTP data is consumed
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= 107 {
+            anyhow::bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .context("PROXY protocol v1 header is not valid text")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: std::net::IpAddr = source
+                .parse()
+                .context(format!("Invalid source address in PROXY header: {}", line))?;
+            let port: u16 = source_port
+                .parse()
+                .context(format!("Invalid source port in PROXY header: {}", line))?;
+            Ok(Some(std::net::SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("Malformed PROXY protocol v1 header: {}", line),
+    }
+}
+
+// Everything except the unreserved characters of RFC 3986
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// reply_markup JSON with one URL button per row, placeholders filled in from the message
+fn build_reply_markup(buttons: &[Button], placeholders: &[(&str, &str)]) -> Option<String> {
+    if buttons.is_empty() {
+        return None;
+    }
+    let rows: Vec<_> = buttons
+        .iter()
+        .map(|button| {
+            let url = placeholders
+                .iter()
+                .fold(button.url.clone(), |url, (name, value)| {
+                    url.replace(
+                        &format!("{{{}}}", name),
+                        &utf8_percent_encode(value, URL_COMPONENT).to_string(),
+                    )
+                });
+            serde_json::json!([{ "text": button.label, "url": url }])
+        })
+        .collect();
+    Some(serde_json::json!({ "inline_keyboard": rows }).to_string())
+}
+
+// Case-insensitive match where '*' stands for any sequence of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last '*' and the text position it was matched against
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] != '*' && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last '*' absorb one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Loads the PEM certificate chain and private key into a TLS acceptor
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let cert_file = std::fs::File::open(cert_path).context(format!(
+        "Failed to open TLS certificate {}",
+        cert_path.display()
+    ))?;
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .context(format!(
+            "Failed to parse TLS certificate {}",
+            cert_path.display()
+        ))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificates found in {}",
+            cert_path.display()
+        ));
+    }
+
+    let key_file = std::fs::File::open(key_path)
+        .context(format!("Failed to open TLS key {}", key_path.display()))?;
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(key_file))
+        .context(format!("Failed to parse TLS key {}", key_path.display()))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path.display()))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate/key pair")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Client connection, upgraded in place by STARTTLS
+pub enum SmtpStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    // Placeholder while the TLS handshake owns the TCP stream
+    Upgrading,
+}
+
+impl SmtpStream {
+    fn is_tls(&self) -> bool {
+        matches!(self, SmtpStream::Tls(_))
+    }
+
+    fn not_connected() -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected, "TLS upgrade in progress")
+    }
+}
+
+impl AsyncRead for SmtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SmtpStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            SmtpStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            SmtpStream::Upgrading => Poll::Ready(Err(SmtpStream::not_connected())),
+        }
+    }
+}
+
+impl AsyncWrite for SmtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SmtpStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            SmtpStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            SmtpStream::Upgrading => Poll::Ready(Err(SmtpStream::not_connected())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SmtpStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            SmtpStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            SmtpStream::Upgrading => Poll::Ready(Err(SmtpStream::not_connected())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SmtpStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            SmtpStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            SmtpStream::Upgrading => Poll::Ready(Err(SmtpStream::not_connected())),
+        }
+    }
+}
+
+// Headers revealing how a message was routed to the relay, used by the `audit` preset
+const AUDIT_HEADERS: &[&str] = &[
+    "Delivered-To",
+    "X-Original-To",
+    "X-Forwarded-To",
+    "Envelope-To",
+];
+
+// Expands header presets and removes duplicates (case-insensitive), keeping the order
+pub fn resolve_include_headers(names: &[String]) -> Vec<String> {
+    let mut resolved: Vec<String> = Vec::new();
+    for name in names {
+        let name = name.trim();
+        let expanded: Vec<String> = if name.eq_ignore_ascii_case("audit") {
+            AUDIT_HEADERS.iter().map(|h| h.to_string()).collect()
+        } else if name.is_empty() {
+            Vec::new()
+        } else {
+            vec![name.to_string()]
+        };
+        for header in expanded {
+            if !resolved.iter().any(|h| h.eq_ignore_ascii_case(&header)) {
+                resolved.push(header);
+            }
+        }
+    }
+    resolved
+}
+
+enum AuthState {
+    None,
+    LoginWaitingUsername,
+    // Holds the username received in the previous step
+    LoginWaitingPassword(String),
+    PlainWaitingData,
+}
+
+// Reply to AUTH when no credentials are configured
+fn auth_not_enabled() -> Response<String> {
+    Response::new(502, 5, 5, 1, "Authentication not enabled".to_string())
+}
+
+// Decodes a base64 SASL response, falling back to the raw text for broken clients
+fn decode_auth_text(encoded: &str) -> String {
+    if encoded.is_empty() {
+        return String::new();
+    }
+    match general_purpose::STANDARD.decode(encoded) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+        Err(_) => encoded.to_string(),
+    }
+}
+
+// Splits decoded AUTH PLAIN data into username and password
+fn parse_plain_credentials(decoded: &[u8]) -> Option<(String, String)> {
+    let parts: Vec<&[u8]> = decoded.split(|&b| b == 0).collect();
+    match parts.len() {
+        // Standard format: authzid\0username\0password
+        n if n >= 3 => Some((
+            String::from_utf8_lossy(parts[1]).to_string(),
+            String::from_utf8_lossy(parts[2]).to_string(),
+        )),
+        // Sometimes format is username\0password
+        2 => Some((
+            String::from_utf8_lossy(parts[0]).to_string(),
+            String::from_utf8_lossy(parts[1]).to_string(),
+        )),
+        _ => None,
+    }
+}
+
+// Compares secrets in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Bot API methods the relay is allowed to call
+const ALLOWED_TELEGRAM_METHODS: &[&str] = &[
+    "getMe",
+    "getChat",
+    "sendMessage",
+    "sendDocument",
+    "sendPhoto",
+    "createForumTopic",
+];
+
+// Envelope of Bot API responses
+#[derive(Deserialize)]
+struct TelegramResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+    parameters: Option<ResponseParameters>,
+}
+
+// Why a message or attachment couldn't be sent to Telegram
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    // No response from the Bot API: connection failure, timeout
+    #[error("Failed to send request to Telegram: {0}")]
+    Network(#[from] reqwest::Error),
+    // Request rejected by the Bot API with a non-success status
+    #[error("Telegram API error: {status} - {body}")]
+    TelegramApi {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("Message text is empty")]
+    EmptyMessage,
+    // Upload refused with 413 by a Bot API server with a lower limit than ours
+    #[error("Attachment {filename} is too large for Telegram ({size} bytes)")]
+    TooLarge { filename: String, size: usize },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl ForwardError {
+    // Failures that retrying the same message won't fix, such as a bad chat ID or a bot
+    // that was removed from the chat. Rate limits, server and network errors are temporary.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            ForwardError::TelegramApi { status, .. } => {
+                status.is_client_error() && *status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            ForwardError::EmptyMessage | ForwardError::TooLarge { .. } => true,
+            ForwardError::Network(_) | ForwardError::Other(_) => false,
+        }
+    }
+}
+
+// The client went away (EOF or reset) while the session was reading from it
+#[derive(Debug, thiserror::Error)]
+#[error("Connection closed by client")]
+struct ClientDisconnected;
+
+// Maps read errors that mean the peer is gone to ClientDisconnected
+fn read_error(e: io::Error, context: &'static str) -> anyhow::Error {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => anyhow::Error::new(ClientDisconnected).context(context),
+        _ => anyhow::Error::new(e).context(context),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResponseParameters {
+    retry_after: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BotUser {
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct ForumTopic {
+    message_thread_id: i64,
+}
+
+// Backoff before retry `attempt` (1-based): 0.5s doubling up to 30s, plus up to 50% jitter
+fn retry_delay(attempt: u32) -> Duration {
+    let base = Duration::from_millis(500)
+        .saturating_mul(1 << attempt.saturating_sub(1).min(6))
+        .min(Duration::from_secs(30));
+    // No need for a real RNG, this only spreads out concurrent retries
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let jitter = base.as_millis() as u64 / 2 * u64::from(nanos % 1000) / 1000;
+    base + Duration::from_millis(jitter)
+}
+
+// How long a 429 response asks to wait: the Retry-After header, or else
+// parameters.retry_after in the JSON body
+async fn telegram_retry_after(response: reqwest::Response) -> Option<Duration> {
+    let header = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let seconds = match header {
+        Some(seconds) => seconds,
+        None => {
+            let body: TelegramResponse<serde::de::IgnoredAny> = response.json().await.ok()?;
+            body.parameters?.retry_after?
+        }
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+// Upper bounds in seconds of the Telegram request latency histogram
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+// Counters exposed on --metrics-bind, collected whether or not the endpoint is enabled
+#[derive(Default)]
+pub struct Metrics {
+    emails_received: AtomicU64,
+    emails_filtered: AtomicU64,
+    messages_forwarded: AtomicU64,
+    chunks_sent: AtomicU64,
+    telegram_errors: AtomicU64,
+    // Cumulative counts per LATENCY_BUCKETS entry, as in the exposition format
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn observe_latency(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                Self::increment(bucket);
+            }
+        }
+        Self::increment(&self.latency_count);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "smtp_to_telegram_emails_received_total",
+                "Emails received over SMTP",
+                &self.emails_received,
+            ),
+            (
+                "smtp_to_telegram_emails_filtered_total",
+                "Emails dropped by the sender and subject filters",
+                &self.emails_filtered,
+            ),
+            (
+                "smtp_to_telegram_messages_forwarded_total",
+                "Emails forwarded to a Telegram chat without errors",
+                &self.messages_forwarded,
+            ),
+            (
+                "smtp_to_telegram_chunks_sent_total",
+                "Parts of split messages sent to Telegram",
+                &self.chunks_sent,
+            ),
+            (
+                "smtp_to_telegram_telegram_errors_total",
+                "Telegram API requests that failed after all retries",
+                &self.telegram_errors,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+
+        let name = "smtp_to_telegram_telegram_request_duration_seconds";
+        out.push_str(&format!(
+            "# HELP {name} Round-trip time of Telegram API requests\n# TYPE {name} histogram\n"
+        ));
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            out.push_str(&format!(
+                "{name}_bucket{{le=\"{}\"}} {}\n",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            ));
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        out.push_str(&format!("{name}_bucket{{le=\"+Inf\"}} {}\n", count));
+        out.push_str(&format!("{name}_sum {}\n{name}_count {}\n", sum, count));
+        out
+    }
+}
+
+// Chat, and optionally forum topic, a message is posted to
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ChatTarget<'a> {
+    chat_id: &'a str,
+    thread_id: Option<i64>,
+}
+
+// Public Bot API server, --api-base-url points elsewhere for a self-hosted one
+pub const DEFAULT_TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+// Builds the Bot API URL for a method, refusing methods outside the allowlist
+fn telegram_method_url(base_url: &str, token: &str, method: &str) -> Result<String> {
+    if !ALLOWED_TELEGRAM_METHODS.contains(&method) {
+        return Err(anyhow::anyhow!(
+            "Telegram API method not allowed: {}",
+            method
+        ));
+    }
+    Ok(format!("{}/bot{}/{}", base_url, token, method))
+}
+
+// Calls getMe to check the token before accepting mail, returns the bot username
+pub async fn check_bot_token(
+    http_client: &reqwest::Client,
+    base_url: &str,
+    token: &str,
+) -> Result<String> {
+    let url = telegram_method_url(base_url, token, "getMe")?;
+    let response = http_client
+        .get(&url)
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context("Failed to reach the Telegram API")?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::NOT_FOUND {
+        return Err(anyhow::anyhow!(
+            "Telegram rejected the bot token ({}), check --token or TELEGRAM_TOKEN",
+            status
+        ));
+    }
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(ForwardError::TelegramApi { status, body }.into());
+    }
+
+    let response: TelegramResponse<BotUser> = response
+        .json()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context("Invalid getMe response from Telegram")?;
+    match response.result {
+        Some(bot) if response.ok => Ok(bot.username.unwrap_or_default()),
+        _ => Err(anyhow::anyhow!(
+            "Telegram API error: {}",
+            response.description.unwrap_or_default()
+        )),
+    }
+}
+
+// Calls getChat to look up the numeric ID of a @username chat
+pub async fn resolve_chat_id(
+    http_client: &reqwest::Client,
+    base_url: &str,
+    token: &str,
+    chat_id: &str,
+) -> Result<i64> {
+    let url = telegram_method_url(base_url, token, "getChat")?;
+    let response = http_client
+        .get(&url)
+        .query(&[("chat_id", chat_id)])
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context("Failed to reach the Telegram API")?;
+
+    let status = response.status();
+    let response: TelegramResponse<Chat> = response
+        .json()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context(format!(
+            "Invalid getChat response from Telegram ({})",
+            status
+        ))?;
+    match response.result {
+        Some(chat) if response.ok => Ok(chat.id),
+        _ => Err(anyhow::anyhow!(
+            "Failed to resolve chat {}: {}",
+            chat_id,
+            response.description.unwrap_or_default()
+        )),
+    }
+}
+
+// Text extracted from an email, before formatting for Telegram
+pub struct ExtractedEmail {
+    pub subject: String,
+    pub message_id: Option<String>,
+    // Value of the X-Telegram-Parse-Mode header
+    pub requested_parse_mode: Option<String>,
+    // From and To headers with display names, e.g. "Alerts <alerts@example.com>"
+    pub header_from: Option<String>,
+    pub header_to: Option<String>,
+    // Display name of the first From address, shown with the envelope sender
+    pub from_name: Option<String>,
+    // Sending time in --timezone
+    pub date: Option<String>,
+    // "Name: value" lines shown above the body (Subject, included headers)
+    pub header_lines: Vec<String>,
+    pub body: String,
+    // Content type of the body that was picked
+    pub content_type: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+// File attached to an email, forwarded as a Telegram photo or document
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    // Images Telegram can display with sendPhoto, other files go through sendDocument
+    fn is_photo(&self) -> bool {
+        self.data.len() <= MAX_PHOTO_SIZE
+            && self
+                .content_type
+                .as_deref()
+                .is_some_and(|ct| PHOTO_CONTENT_TYPES.contains(&ct))
+    }
+}
+
+// Bot API limit for files uploaded by bots, and the higher one of a self-hosted Bot API server
+pub const MAX_DOCUMENT_SIZE: usize = 50 * 1024 * 1024;
+pub const MAX_LOCAL_DOCUMENT_SIZE: usize = 2000 * 1024 * 1024;
+
+// Telegram API limit: 1-4096 characters after entities parsing. Lower limits leave room
+// for the longest "[part i of n]" prefix.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 4096;
+pub const MIN_MAX_MESSAGE_LENGTH: usize = 256;
+
+// Most characters of a long message shown in the caption of its --long-as-document file
+const LONG_MESSAGE_PREVIEW_LENGTH: usize = 300;
+
+// Bot API limits for sendPhoto uploads and media captions
+const MAX_PHOTO_SIZE: usize = 10 * 1024 * 1024;
+const MAX_CAPTION_LENGTH: usize = 1024;
+const PHOTO_CONTENT_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+// Escapes plain text for Telegram's HTML parse mode
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Escapes plain text for Telegram's HTML parse mode and wraps bare URLs in links. The text
+// is plain, so there are no anchors yet and every URL is wrapped exactly once.
+pub fn linkify_plain_text(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut copied = 0;
+    for found in URL_RE.find_iter(text) {
+        let url = trim_url(found.as_str());
+        if url
+            .split_once("://")
+            .is_none_or(|(_, rest)| rest.is_empty())
+        {
+            continue;
+        }
+        html.push_str(&escape_html(&text[copied..found.start()]));
+        html.push_str(&format!("<a href=\"{0}\">{0}</a>", escape_html(url)));
+        copied = found.start() + url.len();
+    }
+    html.push_str(&escape_html(&text[copied..]));
+    html
+}
+
+// Drops punctuation that ends the sentence around a URL rather than the URL itself, and a
+// closing parenthesis that has no opening one in the URL
+fn trim_url(mut url: &str) -> &str {
+    while let Some(last) = url.chars().last() {
+        let unbalanced = last == ')' && url.matches(')').count() > url.matches('(').count();
+        if !(".,;:!?*".contains(last) || unbalanced) {
+            break;
+        }
+        url = &url[..url.len() - 1];
+    }
+    url
+}
+
+// Escapes plain text for Telegram's MarkdownV2 parse mode
+fn escape_markdown_v2(text: &str) -> String {
+    escape_with_backslash(text, "_*[]()~`>#+-=|{}.!\\")
+}
+
+// Escapes plain text for the legacy Markdown parse mode
+fn escape_markdown(text: &str) -> String {
+    escape_with_backslash(text, "_*`[")
+}
+
+fn escape_with_backslash(text: &str, reserved: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if reserved.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Parse mode requested with an X-Telegram-Parse-Mode header, Some(None) for plain text.
+// None when the value isn't a supported mode.
+fn parse_mode_from_header(value: &str) -> Option<Option<&'static str>> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "html" => Some(Some("HTML")),
+        "markdownv2" => Some(Some("MarkdownV2")),
+        "markdown" => Some(Some("Markdown")),
+        "none" | "plain" => Some(None),
+        _ => None,
+    }
+}
+
+// Reverses the escaping of escape_for_parse_mode for text sent without a parse mode
+fn strip_markup(text: &str, parse_mode: &str) -> String {
+    match parse_mode {
+        "HTML" => html_to_plain_text(text),
+        "MarkdownV2" | "Markdown" => {
+            let mut plain = String::with_capacity(text.len());
+            let mut chars = text.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => plain.extend(chars.next()),
+                    _ => plain.push(c),
+                }
+            }
+            plain
+        }
+        _ => text.to_string(),
+    }
+}
+
+// Plain text to send again when Telegram rejected the formatting of text with a 400, None
+// for other errors. Telegram's entity parser is stricter than our sanitizer, this keeps the
+// email from being lost.
+fn plain_text_fallback(
+    status: reqwest::StatusCode,
+    body: &str,
+    text: &str,
+    parse_mode: Option<&str>,
+) -> Option<String> {
+    let mode = parse_mode?;
+    if status != reqwest::StatusCode::BAD_REQUEST || !body.contains("can't parse entities") {
+        return None;
+    }
+    warn!(
+        "Telegram rejected the {} formatting, sending as plain text: {}",
+        mode, body
+    );
+    Some(strip_markup(text, mode))
+}
+
+// Escapes plain text so it's shown literally with the given parse mode
+fn escape_for_parse_mode(text: &str, parse_mode: Option<&str>) -> String {
+    match parse_mode {
+        Some("HTML") => escape_html(text),
+        Some("MarkdownV2") => escape_markdown_v2(text),
+        Some("Markdown") => escape_markdown(text),
+        _ => text.to_string(),
+    }
+}
+
+// Layout of forwarded messages when --template isn't set
+pub const DEFAULT_TEMPLATE: &str = "From: {from}\nTo: {to}\n\n{headers}\n\n{body}";
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &["from", "to", "subject", "date", "headers", "body"];
+pub static TEMPLATE_PLACEHOLDER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{(\w+)\}").unwrap());
+
+// Fills in a message template. A line whose placeholders are all empty is left out, and
+// blank lines of the template don't pile up, so missing values leave no gaps.
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in template.split('\n') {
+        let mut filled = false;
+        let mut placeholders = false;
+        let rendered = TEMPLATE_PLACEHOLDER_RE.replace_all(line, |caps: &regex::Captures| {
+            match values.iter().find(|(name, _)| *name == &caps[1]) {
+                Some((_, value)) => {
+                    placeholders = true;
+                    filled |= !value.is_empty();
+                    value.to_string()
+                }
+                None => caps[0].to_string(),
+            }
+        });
+        if placeholders && !filled {
+            continue;
+        }
+        if rendered.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(rendered.into_owned());
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+// Settings shared by all SMTP sessions
+pub struct SessionConfig {
+    // Replaced in place when the token file is reloaded
+    pub telegram_token: RwLock<String>,
+    // Default destinations when no route matches, each gets the message
+    pub telegram_chat_ids: Vec<String>,
+    pub thread_id: Option<i64>,
+    pub api_base_url: String,
+    // Largest attachment uploaded, bigger ones are reported in a notice
+    pub max_document_size: usize,
+    pub routes: Vec<Route>,
+    pub sender_topics: Vec<SenderTopic>,
+    // Envelope sender patterns and subject pattern a message must pass to be forwarded
+    pub from_allow: Vec<String>,
+    pub from_block: Vec<String>,
+    pub subject_regex: Option<Regex>,
+    pub buttons: Vec<Button>,
+    pub auto_create_topics: bool,
+    // Topics created for senders, keyed by chat and lowercased sender. A cell is set once
+    // the topic exists, a cell being set up holds back mail for that sender only.
+    pub created_topics: Mutex<CreatedTopics>,
+    // --topics-file the created topics are saved to and loaded from at startup
+    pub topics_file: Option<PathBuf>,
+    pub include_headers: Vec<String>,
+    pub allow_parse_mode_header: bool,
+    pub show_auth_results: bool,
+    pub no_whitespace_cleanup: bool,
+    pub chunk_header: bool,
+    pub prefer_header_addresses: bool,
+    pub prefer: PreferredPart,
+    pub strip_quotes: bool,
+    pub no_link_preview: bool,
+    pub silent: bool,
+    // Requests that would be sent are logged instead
+    pub dry_run: bool,
+    pub timezone: Tz,
+    pub show_unsubscribe: bool,
+    pub parse_mode: ParseMode,
+    // Bare URLs in plain text bodies become links, only with the HTML parse mode
+    pub linkify: bool,
+    pub transforms: Vec<BodyTransform>,
+    pub max_message_size: usize,
+    // Longest text sent in one message, longer ones are split into parts
+    pub max_message_length: usize,
+    // How far back from the length limit to look for a paragraph, line or word break
+    pub split_lookback: usize,
+    // Longer text is uploaded as a file instead of being split
+    pub long_as_document: bool,
+    pub max_recipients: usize,
+    pub io_timeout: u64,
+    // Milliseconds the client must stay silent before the greeting
+    pub greeting_delay: u64,
+    // Bounds outbound Telegram requests in flight, shared by all sessions
+    pub telegram_permits: Semaphore,
+    pub max_send_attempts: u32,
+    pub confirm_delivery: bool,
+    // Messages per chat and window, 0 when unlimited
+    pub flood_limit: u32,
+    pub flood_window: Duration,
+    pub flood_windows: Mutex<HashMap<String, FloodWindow>>,
+    // Requests per second across all chats and per chat, 0 when unlimited
+    pub rate_limit: f64,
+    pub chat_rate_limit: f64,
+    // Pause between the parts of a split message
+    pub chunk_delay: Duration,
+    pub rate_buckets: Mutex<(TokenBucket, HashMap<String, TokenBucket>)>,
+    // Zero when deduplication is off
+    pub dedupe_ttl: Duration,
+    pub seen_messages: Mutex<SeenMessages>,
+    pub spool: Option<Spool>,
+    pub metrics: Metrics,
+    // Domain that starts the greeting and the EHLO reply
+    pub hostname: String,
+    pub tls_acceptor: Option<TlsAcceptor>,
+    // Username and password clients must authenticate with; AUTH is refused when unset
+    pub auth_credentials: Option<(String, String)>,
+    // Text forwarded for emails without any text; such emails are dropped when unset
+    pub empty_placeholder: Option<String>,
+    pub attach_eml: Option<AttachEml>,
+    // --template, or DEFAULT_TEMPLATE
+    pub template: String,
+}
+
+impl SessionConfig {
+    pub fn telegram_token(&self) -> String {
+        self.telegram_token.read().unwrap().clone()
+    }
+
+    // Replaces the bot token with the one in the token file, keeping the current token when
+    // the file can't be read
+    pub fn reload_token(&self, path: &Path) -> Result<()> {
+        let token = read_token_file(path)?;
+        info!(
+            "Bot token rotated from {}: {}",
+            path.display(),
+            mask_token(&token)
+        );
+        *self.telegram_token.write().unwrap() = token;
+        Ok(())
+    }
+
+    // Picks the destination chats and topics for a recipient: the first matching route,
+    // otherwise every default chat with --thread-id
+    fn resolve_chats(&self, recipient: &str) -> Vec<ChatTarget<'_>> {
+        match self
+            .routes
+            .iter()
+            .find(|route| wildcard_match(&route.pattern, recipient))
+        {
+            Some(route) => vec![ChatTarget {
+                chat_id: &route.chat_id,
+                thread_id: route.thread_id,
+            }],
+            None => self
+                .telegram_chat_ids
+                .iter()
+                .map(|chat_id| ChatTarget {
+                    chat_id,
+                    thread_id: self.thread_id,
+                })
+                .collect(),
+        }
+    }
+
+    // Why --from-block, --from-allow or --subject-regex drop a message, None when they
+    // let it through
+    fn filter_reason(&self, sender: Option<&str>, subject: &str) -> Option<String> {
+        let sender = sender.unwrap_or_default();
+        if let Some(pattern) = self
+            .from_block
+            .iter()
+            .find(|pattern| wildcard_match(pattern, sender))
+        {
+            return Some(format!(
+                "sender <{}> matches --from-block {}",
+                sender, pattern
+            ));
+        }
+        if !self.from_allow.is_empty()
+            && !self
+                .from_allow
+                .iter()
+                .any(|pattern| wildcard_match(pattern, sender))
+        {
+            return Some(format!("sender <{}> matches no --from-allow", sender));
+        }
+        match &self.subject_regex {
+            Some(regex) if !regex.is_match(subject) => Some(format!(
+                "subject \"{}\" doesn't match --subject-regex",
+                subject
+            )),
+            _ => None,
+        }
+    }
+
+    // Counts a message towards the chat's flood window. Returns None when the message must
+    // be dropped, otherwise the number of messages dropped in the previous window.
+    fn admit_message(&self, destination: ChatTarget<'_>) -> Option<u32> {
+        if self.flood_limit == 0 {
+            return Some(0);
+        }
+
+        let now = Instant::now();
+        let mut windows = self.flood_windows.lock().unwrap();
+        let window = windows
+            .entry(destination.chat_id.to_string())
+            .or_insert(FloodWindow {
+                started: now,
+                forwarded: 0,
+                suppressed: 0,
+                thread_id: destination.thread_id,
+            });
+
+        let mut suppressed_before = 0;
+        if now.duration_since(window.started) >= self.flood_window {
+            suppressed_before = window.suppressed;
+            *window = FloodWindow {
+                started: now,
+                forwarded: 0,
+                suppressed: 0,
+                thread_id: destination.thread_id,
+            };
+        }
+        window.thread_id = destination.thread_id;
+
+        if window.forwarded >= self.flood_limit {
+            window.suppressed += 1;
+            return None;
+        }
+        window.forwarded += 1;
+        Some(suppressed_before)
+    }
+
+    // Ends the flood windows that are over, returning the chats, topics and counts of those
+    // that dropped messages. Their notice is otherwise only sent with the chat's next message.
+    fn take_expired_flood_windows(&self) -> Vec<(String, Option<i64>, u32)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.flood_windows
+            .lock()
+            .unwrap()
+            .retain(|chat_id, window| {
+                if now.duration_since(window.started) < self.flood_window {
+                    return true;
+                }
+                if window.suppressed > 0 {
+                    expired.push((chat_id.clone(), window.thread_id, window.suppressed));
+                }
+                false
+            });
+        expired
+    }
+
+    // Message sent to a chat after its flood window dropped `suppressed` messages
+    fn flood_notice(&self, suppressed: u32) -> String {
+        format!(
+            "⚠️ Suppressed {} message(s) over the flood limit of {} per {} seconds",
+            suppressed,
+            self.flood_limit,
+            self.flood_window.as_secs()
+        )
+    }
+
+    // Whether a message with this key was forwarded within --dedupe-ttl
+    fn is_duplicate(&self, key: &str) -> bool {
+        let mut seen = self.seen_messages.lock().unwrap();
+        seen.expire(self.dedupe_ttl, Instant::now());
+        seen.forwarded_at.contains_key(key)
+    }
+
+    fn remember_forwarded(&self, key: String) {
+        let now = Instant::now();
+        let mut seen = self.seen_messages.lock().unwrap();
+        seen.forwarded_at.insert(key.clone(), now);
+        seen.order.push_back((key, now));
+        seen.expire(self.dedupe_ttl, now);
+    }
+
+    // Takes a token from the global bucket and the chat's bucket for one Bot API request.
+    // Returns how long to wait before trying again when either is empty.
+    fn reserve_request(&self, chat_id: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self.rate_buckets.lock().unwrap();
+        let (global, chats) = &mut *buckets;
+        let chat = chats
+            .entry(chat_id.to_string())
+            .or_insert_with(|| TokenBucket::full(self.chat_rate_limit, now));
+
+        let wait = global
+            .wait_time(self.rate_limit, now)
+            .max(chat.wait_time(self.chat_rate_limit, now));
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+        global.take(self.rate_limit);
+        chat.take(self.chat_rate_limit);
+        None
+    }
+}
+
+// Most Message-IDs remembered for --dedupe-ttl, the oldest are forgotten first
+const DEDUPE_CAPACITY: usize = 10_000;
+
+// Messages forwarded within --dedupe-ttl, keyed by Message-ID and recipients
+#[derive(Default)]
+pub struct SeenMessages {
+    forwarded_at: HashMap<String, Instant>,
+    // Keys in the order they were forwarded
+    order: VecDeque<(String, Instant)>,
+}
+
+impl SeenMessages {
+    fn expire(&mut self, ttl: Duration, now: Instant) {
+        while let Some((key, seen)) = self.order.front() {
+            if now.duration_since(*seen) < ttl && self.order.len() <= DEDUPE_CAPACITY {
+                break;
+            }
+            // A key forwarded again later stays until its newer entry expires
+            if self.forwarded_at.get(key) == Some(seen) {
+                self.forwarded_at.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+// Token bucket holding up to one second's worth of requests (at least one), refilled
+// continuously at the configured rate
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn full(rate: f64, now: Instant) -> Self {
+        Self {
+            tokens: rate.max(1.0),
+            updated: now,
+        }
+    }
+
+    // Refills the bucket and returns how long until it holds a whole token (zero when it
+    // does, or when the rate is 0 and unlimited)
+    fn wait_time(&mut self, rate: f64, now: Instant) -> Duration {
+        if rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / rate)
+        }
+    }
+
+    fn take(&mut self, rate: f64) {
+        if rate > 0.0 {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+// Topics created with --auto-create-topics, keyed by chat and lowercased sender
+pub type CreatedTopics = HashMap<(String, String), Arc<tokio::sync::OnceCell<i64>>>;
+
+// Reads the topics saved to --topics-file, one CHAT_ID<tab>SENDER<tab>THREAD_ID line each.
+// A file that doesn't exist yet has no topics.
+pub fn load_created_topics(path: &Path) -> Result<CreatedTopics> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+    let mut topics = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split('\t');
+        let (Some(chat_id), Some(sender), Some(thread_id), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow::anyhow!(
+                "Invalid line in {}: {}",
+                path.display(),
+                line
+            ));
+        };
+        let thread_id = thread_id.parse().context(format!(
+            "Invalid thread id in {}: {}",
+            path.display(),
+            line
+        ))?;
+        topics.insert(
+            (chat_id.to_string(), sender.to_string()),
+            Arc::new(tokio::sync::OnceCell::new_with(Some(thread_id))),
+        );
+    }
+    Ok(topics)
+}
+
+// Appends a created topic to --topics-file
+async fn save_created_topic(path: &Path, key: &(String, String), thread_id: i64) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context(format!("Failed to open {}", path.display()))?;
+    let line = format!("{}\t{}\t{}\n", key.0, key.1, thread_id);
+    file.write_all(line.as_bytes())
+        .await
+        .context(format!("Failed to write {}", path.display()))?;
+    file.flush()
+        .await
+        .context(format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+// Messages forwarded to and dropped for a chat since the window started, and the topic the
+// notice about dropped messages goes to
+pub struct FloodWindow {
+    started: Instant,
+    forwarded: u32,
+    suppressed: u32,
+    thread_id: Option<i64>,
+}
+
+pub struct SmtpSession {
+    // Lines are read from the buffer; writes go straight to the stream
+    stream: BufReader<SmtpStream>,
+    telegram: TelegramClient,
+    config: Arc<SessionConfig>,
+    // Connection number, also the first part of the "queued as" id of each message
+    id: u64,
+    messages: u64,
+    buffer: Vec<u8>,
+    auth_state: AuthState,
+    authenticated: bool,
+}
+
+impl SmtpSession {
+    pub fn new(stream: SmtpStream, id: u64, telegram: TelegramClient) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            config: Arc::clone(&telegram.config),
+            telegram,
+            id,
+            messages: 0,
+            buffer: Vec::new(),
+            auth_state: AuthState::None,
+            authenticated: false,
+        }
+    }
+
+    async fn send_response(&mut self, response: Response<String>) -> Result<()> {
+        let mut buf = Vec::new();
+        response
+            .write(&mut buf)
+            .context("Failed to format response")?;
+        self.stream
+            .write_all(&buf)
+            .await
+            .context("Failed to write response")?;
+        self.stream
+            .flush()
+            .await
+            .context("Failed to flush response")?;
+        Ok(())
+    }
+
+    // ESMTP extensions advertised in the EHLO reply, depending on configuration and on
+    // whether the connection is already encrypted
+    fn ehlo_capabilities(&self) -> Vec<String> {
+        let mut capabilities = vec![
+            "PIPELINING".to_string(),
+            format!("SIZE {}", self.config.max_message_size),
+            "8BITMIME".to_string(),
+            "CHUNKING".to_string(),
+            // Addresses are parsed and forwarded as UTF-8 (RFC 6531)
+            "SMTPUTF8".to_string(),
+        ];
+        if self.config.tls_acceptor.is_some() && !self.stream.get_ref().is_tls() {
+            capabilities.push("STARTTLS".to_string());
+        }
+        if self.config.auth_credentials.is_some() {
+            capabilities.push("AUTH PLAIN LOGIN".to_string());
+        }
+        capabilities
+    }
+
+    // Sends the 220 banner, which must start with the server's domain (RFC 5321 section 4.2),
+    // so it has no enhanced status code
+    async fn send_greeting(&mut self) -> Result<()> {
+        let line = format!(
+            "220 {} ESMTP SMTP to Telegram Service Ready\r\n",
+            self.config.hostname
+        );
+        self.stream
+            .write_all(line.as_bytes())
+            .await
+            .context("Failed to write greeting")?;
+        self.stream
+            .flush()
+            .await
+            .context("Failed to flush greeting")?;
+        Ok(())
+    }
+
+    // Sends a 334 SASL challenge; its text must be bare base64 without an enhanced status code
+    async fn send_auth_challenge(&mut self, challenge: &str) -> Result<()> {
+        let line = format!("334 {}\r\n", challenge);
+        self.stream
+            .write_all(line.as_bytes())
+            .await
+            .context("Failed to write AUTH challenge")?;
+        self.stream
+            .flush()
+            .await
+            .context("Failed to flush AUTH challenge")?;
+        Ok(())
+    }
+
+    // Verifies credentials and replies with the AUTH outcome
+    async fn finish_auth(&mut self, mechanism: &str, username: &str, password: &str) -> Result<()> {
+        let Some((expected_user, expected_pass)) = &self.config.auth_credentials else {
+            return self.send_response(auth_not_enabled()).await;
+        };
+
+        // Evaluate both comparisons so timing doesn't reveal which one failed
+        let user_ok = constant_time_eq(username.as_bytes(), expected_user.as_bytes());
+        let pass_ok = constant_time_eq(password.as_bytes(), expected_pass.as_bytes());
+        if user_ok & pass_ok {
+            info!("AUTH {} succeeded for {}", mechanism, username);
+            self.authenticated = true;
+            self.send_response(Response::new(
+                235,
+                0,
+                0,
+                0,
+                "Authentication successful".to_string(),
+            ))
+            .await
+        } else {
+            warn!("AUTH {} failed for {}", mechanism, username);
+            self.send_response(Response::new(
+                535,
+                0,
+                0,
+                0,
+                "Authentication failed".to_string(),
+            ))
+            .await
+        }
+    }
+
+    // Handles base64 AUTH PLAIN data (\0username\0password)
+    async fn finish_plain_auth(&mut self, encoded: &str) -> Result<()> {
+        let decoded = if encoded.is_empty() {
+            Vec::new()
+        } else {
+            match general_purpose::STANDARD.decode(encoded) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    return self
+                        .send_response(Response::new(
+                            535,
+                            0,
+                            0,
+                            0,
+                            "Authentication failed".to_string(),
+                        ))
+                        .await;
+                }
+            }
+        };
+
+        match parse_plain_credentials(&decoded) {
+            Some((username, password)) => self.finish_auth("PLAIN", &username, &password).await,
+            None => {
+                self.send_response(Response::new(
+                    535,
+                    0,
+                    0,
+                    0,
+                    "Authentication failed".to_string(),
+                ))
+                .await
+            }
+        }
+    }
+
+    // Performs the TLS handshake on the underlying TCP connection (RFC 3207)
+    async fn upgrade_to_tls(&mut self, acceptor: TlsAcceptor) -> Result<()> {
+        let pipelined = self.stream.buffer().len();
+        let SmtpStream::Plain(tcp) =
+            std::mem::replace(self.stream.get_mut(), SmtpStream::Upgrading)
+        else {
+            return Err(anyhow::anyhow!("Connection is not in plain-text mode"));
+        };
+        if pipelined > 0 {
+            info!(
+                "Discarding {} bytes sent after STARTTLS before the TLS handshake",
+                pipelined
+            );
+        }
+        let tls = acceptor.accept(tcp).await.context("TLS handshake failed")?;
+        // A new buffer, so that plaintext pipelined after STARTTLS is never read as if it
+        // had arrived over TLS (RFC 3207)
+        self.stream = BufReader::new(SmtpStream::Tls(Box::new(tls)));
+        Ok(())
+    }
+
+    // Forwards the complete message in the buffer and replies to the client, after the DATA
+    // terminator or the last BDAT chunk
+    async fn deliver_message(&mut self, mail_from: Option<&str>, rcpt_to: &[String]) -> Result<()> {
+        // Logged with every line about this message and returned to the client,
+        // so a delivery can be traced from the sender's logs
+        self.messages += 1;
+        let message_id = format!("{}.{}", self.id, self.messages);
+        let span = tracing::info_span!("message", id = %message_id);
+        Metrics::increment(&self.config.metrics.emails_received);
+        let forwarded = self
+            .telegram
+            .forward_message(&self.buffer, mail_from, rcpt_to, None)
+            .instrument(span.clone())
+            .await;
+
+        // Once spooled, the message is ours to retry and the client can let go of it
+        let forwarded = match forwarded {
+            Err(failed) if self.config.spool.is_some() && !failed.error.is_permanent() => {
+                match self
+                    .telegram
+                    .spool_message(&self.buffer, mail_from, &failed)
+                    .instrument(span.clone())
+                    .await
+                {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        span.in_scope(|| error!("Failed to spool message: {:#}", e));
+                        Err(failed)
+                    }
+                }
+            }
+            forwarded => forwarded,
+        };
+
+        self.buffer.clear();
+
+        // With --confirm-delivery the client keeps the message and retries later
+        // (451) or bounces it (550) when Telegram didn't take it
+        let response = match forwarded {
+            Err(failed) if self.config.confirm_delivery => {
+                if failed.error.is_permanent() {
+                    Response::new(
+                        550,
+                        0,
+                        0,
+                        0,
+                        "Requested action not taken: rejected by Telegram".to_string(),
+                    )
+                } else {
+                    Response::new(
+                        451,
+                        0,
+                        0,
+                        0,
+                        "Requested action aborted: local error".to_string(),
+                    )
+                }
+            }
+            _ => Response::new(250, 0, 0, 0, format!("OK, queued as {}", message_id)),
+        };
+        self.send_response(response).await
+    }
+
+    // Reads a BDAT chunk of exactly `size` octets into the buffer, or drops it when `discard`
+    // is set. There is no dot-stuffing, and every read is bounded by --io-timeout.
+    async fn read_bdat_chunk(&mut self, size: usize, discard: bool) -> Result<()> {
+        let io_timeout = Duration::from_secs(self.config.io_timeout);
+        let mut piece = vec![0u8; size.min(64 * 1024)];
+        let mut remaining = size;
+        while remaining > 0 {
+            let want = remaining.min(piece.len());
+            let read = match tokio::time::timeout(io_timeout, self.stream.read(&mut piece[..want]))
+                .await
+            {
+                Ok(result) => result.map_err(|e| read_error(e, "Failed to read BDAT chunk"))?,
+                Err(_) => {
+                    // Best effort, the client may not be reading either
+                    let _ = self
+                        .send_response(Response::new(421, 0, 0, 0, "Timeout".to_string()))
+                        .await;
+                    return Err(anyhow::anyhow!(
+                        "No BDAT data from client within {}s",
+                        self.config.io_timeout
+                    ));
+                }
+            };
+            if read == 0 {
+                return Err(anyhow::Error::new(ClientDisconnected)
+                    .context(format!("{} bytes of the BDAT chunk missing", remaining)));
+            }
+            if !discard {
+                self.buffer.extend_from_slice(&piece[..read]);
+            }
+            remaining -= read;
+        }
+        Ok(())
+    }
+
+    // Reads one CRLF-terminated line, closing the connection with 421 when the client
+    // doesn't complete it within --io-timeout
+    async fn read_line_bytes(&mut self) -> Result<Vec<u8>> {
+        let io_timeout = Duration::from_secs(self.config.io_timeout);
+        match tokio::time::timeout(io_timeout, self.read_line_bytes_untimed()).await {
+            Ok(result) => result,
+            Err(_) => {
+                // Best effort, the client may not be reading either
+                let _ = self
+                    .send_response(Response::new(421, 0, 0, 0, "Timeout".to_string()))
+                    .await;
+                Err(anyhow::anyhow!(
+                    "No complete line from client within {}s",
+                    self.config.io_timeout
+                ))
+            }
+        }
+    }
+
+    async fn read_line_bytes_untimed(&mut self) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+
+        loop {
+            let available = self
+                .stream
+                .fill_buf()
+                .await
+                .map_err(|e| read_error(e, "Failed to read from stream"))?;
+
+            if available.is_empty() {
+                return Err(ClientDisconnected.into());
+            }
+
+            // Take everything up to and including the next LF
+            let (used, found_lf) = match available.iter().position(|&b| b == b'\n') {
+                Some(pos) => (pos + 1, true),
+                None => (available.len(), false),
+            };
+            line.extend_from_slice(&available[..used]);
+            self.stream.consume(used);
+
+            // A line this long can't belong to an acceptable message, stop buffering it
+            if line.len() > self.config.max_message_size {
+                return Err(anyhow::anyhow!("Line exceeds the maximum message size"));
+            }
+
+            // Some scripts end lines with a bare LF, which is read as CRLF so that commands
+            // and the end of DATA are recognized either way
+            if found_lf {
+                if !line.ends_with(b"\r\n") {
+                    line.pop();
+                    line.extend_from_slice(b"\r\n");
+                }
+                return Ok(line);
+            }
+        }
+    }
+}
+
+// Sends emails to Telegram for the SMTP sessions and the spool
+#[derive(Clone)]
+pub struct TelegramClient {
+    http_client: Arc<reqwest::Client>,
+    config: Arc<SessionConfig>,
+}
+
+impl TelegramClient {
+    pub fn new(http_client: Arc<reqwest::Client>, config: Arc<SessionConfig>) -> Self {
+        Self {
+            http_client,
+            config,
+        }
+    }
+
+    // Waits until fewer than --max-telegram-concurrency requests are in flight
+    async fn acquire_telegram_permit(&self) -> Result<SemaphorePermit<'_>> {
+        self.config
+            .telegram_permits
+            .acquire()
+            .await
+            .context("Telegram request limiter closed")
+    }
+
+    // Waits until --rate-limit and --chat-rate-limit allow another request to the chat
+    async fn wait_for_rate_limit(&self, chat_id: &str) {
+        while let Some(wait) = self.config.reserve_request(chat_id) {
+            debug!("Rate limit reached, waiting {}ms", wait.as_millis());
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Sends a Bot API request for a chat built by `build_request`, retrying 429, 5xx and
+    // connection failures up to --max-send-attempts times. A 429 waits as long as Telegram
+    // asks, otherwise exponential backoff with jitter is used. Other responses, including
+    // 4xx errors, are returned to the caller right away. Every attempt passes the rate limits.
+    async fn send_telegram_request<F>(
+        &self,
+        chat_id: &str,
+        build_request: F,
+    ) -> Result<reqwest::Response, ForwardError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let max_attempts = self.config.max_send_attempts;
+        let mut attempt = 1;
+        loop {
+            self.wait_for_rate_limit(chat_id).await;
+            let result = {
+                let _permit = self.acquire_telegram_permit().await?;
+                let started = Instant::now();
+                // Errors would show the request URL, which contains the bot token
+                let result = build_request()
+                    .send()
+                    .await
+                    .map_err(reqwest::Error::without_url);
+                self.config.metrics.observe_latency(started.elapsed());
+                result
+            };
+
+            let failure = match &result {
+                Ok(response)
+                    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error() =>
+                {
+                    Some(response.status().to_string())
+                }
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_timeout() => Some(e.to_string()),
+                Err(_) => None,
+            };
+            let Some(failure) = failure.filter(|_| attempt < max_attempts) else {
+                if !matches!(&result, Ok(response) if response.status().is_success()) {
+                    Metrics::increment(&self.config.metrics.telegram_errors);
+                }
+                return Ok(result?);
+            };
+
+            let retry_after = match result {
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    telegram_retry_after(response).await
+                }
+                _ => None,
+            };
+            let delay = retry_after.unwrap_or_else(|| retry_delay(attempt));
+            warn!(
+                "Telegram request failed ({}), retrying in {}ms (attempt {}/{})",
+                failure,
+                delay.as_millis(),
+                attempt + 1,
+                max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_to_telegram(
+        &self,
+        target: ChatTarget<'_>,
+        text: &str,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+        chunk_label: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        self.send_to_telegram_internal(target, text, parse_mode, reply_markup, chunk_label)
+            .await
+    }
+
+    // The reply_markup (inline keyboard) is attached to the last part of a split message.
+    // A chunk_label (--chunk-header) is repeated in the prefix of every part.
+    async fn send_to_telegram_internal(
+        &self,
+        target: ChatTarget<'_>,
+        text: &str,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+        chunk_label: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        // Check if text is empty or too short
+        if text.trim().is_empty() {
+            return Err(ForwardError::EmptyMessage);
+        }
+
+        let is_html = parse_mode == Some("HTML");
+        let length = message_len(text, parse_mode);
+
+        // If message fits in one part, send it directly
+        let max_length = self.config.max_message_length;
+        if length <= max_length {
+            return self
+                .send_message(target, text, parse_mode, reply_markup)
+                .await;
+        }
+
+        // The document path can come back here with a notice when the file is too large
+        if self.config.long_as_document {
+            return Box::pin(self.send_as_document(target, text, parse_mode, reply_markup)).await;
+        }
+
+        // Prefix of every part, e.g. "[2/3]" or "[part 2 of 3] #1a2b3c Subject". Brackets are
+        // reserved characters in MarkdownV2.
+        let chunk_prefix = |number: usize, total: usize| {
+            let prefix = match chunk_label {
+                Some(label) => format!("[part {} of {}] {}\n\n", number, total, label),
+                None => format!("[{}/{}]\n\n", number, total),
+            };
+            escape_for_parse_mode(&prefix, parse_mode)
+        };
+
+        // Split long message into chunks
+        let lookback = self.config.split_lookback;
+        let chunks = if is_html {
+            let split = |html: &str, max: usize| split_html_chunks(html, max, lookback);
+            split_with_prefix(text, max_length, split, chunk_prefix)
+        } else {
+            let split = |text: &str, max: usize| split_message_with_lookback(text, max, lookback);
+            split_with_prefix(text, max_length, split, chunk_prefix)
+        };
+
+        // Send each chunk
+        for (index, chunk) in chunks.iter().enumerate() {
+            let last = index == chunks.len() - 1;
+            if let Err(e) = self
+                .send_message(target, chunk, parse_mode, reply_markup.filter(|_| last))
+                .await
+            {
+                warn!(
+                    "Failed to send chunk {}/{} to Telegram",
+                    index + 1,
+                    chunks.len()
+                );
+                return Err(e);
+            }
+            Metrics::increment(&self.config.metrics.chunks_sent);
+
+            // Small delay between messages to avoid rate limiting
+            if index < chunks.len() - 1 {
+                tokio::time::sleep(self.config.chunk_delay).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Uploads text too long for one message as message.txt, with its opening lines (From, To
+    // and Subject with the default template) as the caption
+    async fn send_as_document(
+        &self,
+        target: ChatTarget<'_>,
+        text: &str,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        let plain = match parse_mode {
+            Some(mode) => strip_markup(text, mode),
+            None => text.to_string(),
+        };
+        let preview = split_message(&plain, LONG_MESSAGE_PREVIEW_LENGTH)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let caption = format!("{}\n\n📄 Full text in message.txt", preview.trim_end());
+        let document = Attachment {
+            filename: "message.txt".to_string(),
+            content_type: Some("text/plain; charset=utf-8".to_string()),
+            data: plain.into_bytes(),
+        };
+        self.send_attachment(target, &document, Some(&caption), None, reply_markup)
+            .await
+    }
+
+    // Sends one sendMessage request, again as plain text when Telegram rejects the formatting
+    async fn send_message(
+        &self,
+        target: ChatTarget<'_>,
+        text: &str,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        let url = telegram_method_url(
+            &self.config.api_base_url,
+            &self.config.telegram_token(),
+            "sendMessage",
+        )?;
+
+        debug!(
+            "Calling sendMessage for chat {} ({} characters)",
+            target.chat_id,
+            text.chars().count()
+        );
+
+        if self.config.dry_run {
+            info!(
+                "Dry run: sendMessage to chat {}{} with parse mode {}:\n{}",
+                target.chat_id,
+                target
+                    .thread_id
+                    .map(|id| format!(" (thread {})", id))
+                    .unwrap_or_default(),
+                parse_mode.unwrap_or("none"),
+                text
+            );
+            return Ok(());
+        }
+
+        // Build form data
+        let thread_id = target.thread_id.map(|id| id.to_string());
+        let mut form_data = vec![("chat_id", target.chat_id), ("text", text)];
+
+        // Add parse_mode if specified
+        if let Some(mode) = parse_mode {
+            form_data.push(("parse_mode", mode));
+        }
+        if let Some(thread_id) = &thread_id {
+            form_data.push(("message_thread_id", thread_id));
+        }
+        if let Some(markup) = reply_markup {
+            form_data.push(("reply_markup", markup));
+        }
+        if self.config.no_link_preview {
+            form_data.push(("disable_web_page_preview", "true"));
+        }
+        if self.config.silent {
+            form_data.push(("disable_notification", "true"));
+        }
+
+        let response = self
+            .send_telegram_request(target.chat_id, || {
+                self.http_client.post(&url).form(&form_data)
+            })
+            .await?;
+
+        if response.status().is_success() {
+            return Ok(());
+        }
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if let Some(plain) = plain_text_fallback(status, &body, text, parse_mode) {
+            return Box::pin(self.send_message(target, &plain, None, reply_markup)).await;
+        }
+        Err(ForwardError::TelegramApi { status, body })
+    }
+
+    // Uploads an attachment with sendPhoto or sendDocument, or reports it when it's over the
+    // size limit. Photos rejected by Telegram (e.g. unsupported dimensions) are retried as documents.
+    async fn send_attachment(
+        &self,
+        target: ChatTarget<'_>,
+        attachment: &Attachment,
+        caption: Option<&str>,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        if attachment.data.len() > self.config.max_document_size {
+            warn!(
+                "Attachment {} is too large ({} bytes), skipping upload",
+                attachment.filename,
+                attachment.data.len()
+            );
+            let notice = format!(
+                "Attachment {} ({} MB) was not forwarded: Telegram limits bot uploads to {} MB",
+                attachment.filename,
+                attachment.data.len() / (1024 * 1024),
+                self.config.max_document_size / (1024 * 1024)
+            );
+            return self
+                .send_to_telegram(target, &notice, None, None, None)
+                .await;
+        }
+
+        if attachment.is_photo() {
+            match self
+                .upload_file(
+                    target,
+                    "sendPhoto",
+                    attachment,
+                    caption,
+                    parse_mode,
+                    reply_markup,
+                )
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!(
+                    "Failed to send {} as photo ({}), sending as document",
+                    attachment.filename, e
+                ),
+            }
+        }
+
+        self.upload_file(
+            target,
+            "sendDocument",
+            attachment,
+            caption,
+            parse_mode,
+            reply_markup,
+        )
+        .await
+    }
+
+    // Uploads a file as multipart form data with sendPhoto or sendDocument, again with a plain
+    // text caption when Telegram rejects the caption's formatting
+    async fn upload_file(
+        &self,
+        target: ChatTarget<'_>,
+        method: &str,
+        attachment: &Attachment,
+        caption: Option<&str>,
+        parse_mode: Option<&str>,
+        reply_markup: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        let url = telegram_method_url(
+            &self.config.api_base_url,
+            &self.config.telegram_token(),
+            method,
+        )?;
+        if self.config.dry_run {
+            info!(
+                "Dry run: {} {} ({} bytes) to chat {}{}{}",
+                method,
+                attachment.filename,
+                attachment.data.len(),
+                target.chat_id,
+                target
+                    .thread_id
+                    .map(|id| format!(" (thread {})", id))
+                    .unwrap_or_default(),
+                caption
+                    .map(|caption| format!(
+                        " with parse mode {} and caption:\n{}",
+                        parse_mode.unwrap_or("none"),
+                        caption
+                    ))
+                    .unwrap_or_default()
+            );
+            return Ok(());
+        }
+        let field = if method == "sendPhoto" {
+            "photo"
+        } else {
+            "document"
+        };
+
+        let part = || {
+            reqwest::multipart::Part::bytes(attachment.data.clone())
+                .file_name(attachment.filename.clone())
+        };
+        // Malformed types are uploaded without a MIME type
+        let document = || match &attachment.content_type {
+            Some(ct) => part().mime_str(ct).unwrap_or_else(|_| part()),
+            None => part(),
+        };
+
+        // Multipart forms can't be reused, so each attempt builds its own
+        let build_form = || {
+            let mut form = reqwest::multipart::Form::new()
+                .text("chat_id", target.chat_id.to_string())
+                .part(field.to_string(), document());
+            if let Some(thread_id) = target.thread_id {
+                form = form.text("message_thread_id", thread_id.to_string());
+            }
+            if let Some(caption) = caption {
+                form = form.text("caption", caption.to_string());
+                if let Some(mode) = parse_mode {
+                    form = form.text("parse_mode", mode.to_string());
+                }
+            }
+            if let Some(markup) = reply_markup {
+                form = form.text("reply_markup", markup.to_string());
+            }
+            if self.config.silent {
+                form = form.text("disable_notification", "true");
+            }
+            form
+        };
+
+        let response = self
+            .send_telegram_request(target.chat_id, || {
+                self.http_client.post(&url).multipart(build_form())
+            })
+            .await?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+            return Err(ForwardError::TooLarge {
+                filename: attachment.filename.clone(),
+                size: attachment.data.len(),
+            });
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let plain =
+                caption.and_then(|caption| plain_text_fallback(status, &body, caption, parse_mode));
+            if let Some(plain) = plain {
+                return Box::pin(self.upload_file(
+                    target,
+                    method,
+                    attachment,
+                    Some(&plain),
+                    None,
+                    reply_markup,
+                ))
+                .await;
+            }
+            return Err(ForwardError::TelegramApi { status, body });
+        }
+
+        info!(
+            "Attachment {} forwarded to Telegram with {}",
+            attachment.filename, method
+        );
+        Ok(())
+    }
+
+    pub fn convert_html_to_telegram(&self, html: &str) -> String {
+        let preprocessed = preprocess_html_blocks(html);
+        TELEGRAM_HTML_SANITIZER.clean(&preprocessed).to_string()
+    }
+
+    pub fn extract_text_from_email(&self, email_data: &[u8]) -> ExtractedEmail {
+        // Use mail-parser to parse the email message
+        // mail-parser automatically handles all encodings (base64, quoted-printable, etc.)
+        // and returns text in UTF-8
+        let parser = MessageParser::default();
+
+        if let Some(msg) = parser.parse(email_data) {
+            // Get Content-Type
+            let content_type = msg.content_type().and_then(|ct| {
+                ct.subtype().map(|subtype| {
+                    let mime_type = format!("{}/{}", ct.ctype(), subtype);
+                    mime_type.to_lowercase()
+                })
+            });
+
+            if let Some(ref ct) = content_type {
+                debug!("Found Content-Type: {}", ct);
+            } else {
+                debug!("Content-Type not found in email headers");
+            }
+
+            // Get subject in UTF-8
+            let subject = if let Some(subj) = msg.subject() {
+                debug!("Subject: {}", subj);
+                subj.to_string()
+            } else {
+                debug!("Subject not found in email headers");
+                String::new()
+            };
+
+            let message_id = msg.message_id().map(str::to_string);
+
+            let header_from = msg
+                .from()
+                .map(format_address_list)
+                .filter(|from| !from.is_empty());
+            let from_name = msg
+                .from()
+                .and_then(|from| from.first())
+                .and_then(|addr| addr.name.as_deref())
+                .map(str::trim)
+                .filter(|name| !name.is_empty() && !name.contains('@'))
+                .map(str::to_string);
+            let header_to = msg
+                .to()
+                .map(format_address_list)
+                .filter(|to| !to.is_empty());
+
+            // Sending time, so that delayed mail is recognizable. Missing or unparseable
+            // dates are left out.
+            let date = msg
+                .date()
+                .filter(|date| date.is_valid())
+                .and_then(|date| chrono::DateTime::from_timestamp(date.to_timestamp(), 0))
+                .map(|date| {
+                    let local = date.with_timezone(&self.config.timezone);
+                    local.format("%Y-%m-%d %H:%M:%S %Z").to_string()
+                });
+            let date_line = date.as_ref().map(|date| format!("Date: {}", date));
+
+            // Get configured extra headers (a header may occur several times, e.g. Delivered-To)
+            let mut extra_headers = Vec::new();
+
+            // Cc recipients aren't part of the envelope shown in From/To. Skipped when Cc is
+            // already an --include-header, which shows it raw.
+            let cc_included = self
+                .config
+                .include_headers
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case("Cc"));
+            if let Some(cc) = msg.cc().filter(|_| !cc_included) {
+                let addresses = format_address_list(cc);
+                if !addresses.is_empty() {
+                    debug!("Cc: {}", addresses);
+                    extra_headers.push(format!("Cc: {}", addresses));
+                }
+            }
+
+            for name in &self.config.include_headers {
+                for (_, value) in msg
+                    .headers_raw()
+                    .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+                {
+                    // Unfold the raw value into a single line
+                    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if !value.is_empty() {
+                        debug!("{}: {}", name, value);
+                        extra_headers.push(format!("{}: {}", name, value));
+                    }
+                }
+            }
+
+            // Unfolded values of every occurrence of a header
+            let raw_values = |name: &str| -> Vec<String> {
+                msg.headers_raw()
+                    .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .collect()
+            };
+
+            let requested_parse_mode = raw_values("X-Telegram-Parse-Mode").into_iter().next();
+
+            // Summarize sender authentication results for phishing triage
+            if self.config.show_auth_results {
+                let auth_results = raw_values("Authentication-Results");
+                let received_spf = raw_values("Received-SPF");
+                if let Some(summary) = summarize_auth_results(&auth_results, &received_spf) {
+                    debug!("Auth: {}", summary);
+                    extra_headers.push(format!("Auth: {}", summary));
+                }
+            }
+
+            // Let newsletter recipients unsubscribe straight from the chat
+            if self.config.show_unsubscribe {
+                let mut links: Vec<String> = raw_values("List-Unsubscribe")
+                    .iter()
+                    .flat_map(|value| parse_list_unsubscribe(value))
+                    .collect();
+                if !links.is_empty() {
+                    // RFC 8058: the https link unsubscribes with a single POST
+                    let one_click = raw_values("List-Unsubscribe-Post")
+                        .iter()
+                        .any(|value| value.eq_ignore_ascii_case("List-Unsubscribe=One-Click"));
+                    if one_click && links[0].to_ascii_lowercase().starts_with("http") {
+                        links[0].push_str(" (one-click)");
+                    }
+                    let line = format!("Unsubscribe: {}", links.join(", "));
+                    debug!("{}", line);
+                    extra_headers.push(line);
+                }
+            }
+
+            // Collect attachments to upload after the text
+            let attachments: Vec<Attachment> = msg
+                .attachments()
+                .enumerate()
+                .map(|(index, part)| {
+                    let filename = part
+                        .attachment_name()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("attachment-{}", index + 1));
+                    let content_type = part.content_type().map(|ct| match ct.subtype() {
+                        Some(subtype) => format!("{}/{}", ct.ctype(), subtype).to_lowercase(),
+                        None => ct.ctype().to_lowercase(),
+                    });
+                    debug!(
+                        "Found attachment: {} ({} bytes)",
+                        filename,
+                        part.contents().len()
+                    );
+                    Attachment {
+                        filename,
+                        content_type,
+                        data: part.contents().to_vec(),
+                    }
+                })
+                .collect();
+
+            // Multipart messages (mixed, related, alternative) commonly carry the renderable
+            // content in an HTML part - forward that part as HTML when one exists, unless
+            // --prefer text asks for the text/plain part. For HTML-only messages the text
+            // part is the HTML part itself.
+            let has_html_part = msg.html_part(0).is_some_and(|part| part.is_text_html());
+            let has_plain_part = msg.text_part(0).is_some_and(|part| !part.is_text_html());
+            let mut content_type = match content_type {
+                Some(ct)
+                    if ct.starts_with("multipart/")
+                        && self.config.prefer == PreferredPart::Text
+                        && has_plain_part =>
+                {
+                    debug!("Multipart message contains a plain text part, using text body");
+                    Some("text/plain".to_string())
+                }
+                Some(ct) if ct.starts_with("multipart/") && has_html_part => {
+                    debug!("Multipart message contains an HTML part, using HTML body");
+                    Some("text/html".to_string())
+                }
+                other => other,
+            };
+
+            // Get body - use HTML if Content-Type is text/html, otherwise use text
+            let declared_html = content_type
+                .as_deref()
+                .map(|ct| ct.starts_with("text/html"));
+            let body = if let Some(declared_html) = declared_html {
+                let (declared, other) = if declared_html {
+                    ("HTML", "text")
+                } else {
+                    ("text", "HTML")
+                };
+                let body = if declared_html {
+                    msg.body_html(0)
+                } else {
+                    msg.body_text(0)
+                }
+                .unwrap_or_default();
+                if !body.trim().is_empty() {
+                    debug!("Using the {} body", declared);
+                    body
+                } else {
+                    // Structurally unusual messages can declare one kind of content while the
+                    // renderable part is the other, try that before forwarding nothing
+                    let fallback = if declared_html {
+                        msg.body_text(0)
+                    } else {
+                        msg.body_html(0)
+                    }
+                    .unwrap_or_default();
+                    // An empty message still has an HTML body, mail-parser's empty skeleton
+                    let has_content = if declared_html {
+                        !fallback.trim().is_empty()
+                    } else {
+                        html_has_text(&fallback)
+                    };
+                    if has_content {
+                        info!("The {} body is empty, using the {} body", declared, other);
+                        let used = if declared_html {
+                            "text/plain"
+                        } else {
+                            "text/html"
+                        };
+                        content_type = Some(used.to_string());
+                        fallback
+                    } else {
+                        Cow::Borrowed("")
+                    }
+                }
+            } else {
+                // If no Content-Type, try text first, then HTML
+                let text_body = msg.body_text(0).unwrap_or_default();
+                if !text_body.is_empty() {
+                    debug!("Using the text body");
+                    text_body
+                } else {
+                    // An empty message still has an HTML body, mail-parser's empty skeleton
+                    let html_body = msg.body_html(0).unwrap_or_default();
+                    if html_has_text(&html_body) {
+                        debug!("Text body is empty, using the HTML body");
+                        html_body
+                    } else {
+                        Cow::Borrowed("")
+                    }
+                }
+            };
+
+            if !body.trim().is_empty() {
+                // Clean up extra whitespace from body, or only drop the line breaks around it
+                // with --no-whitespace-cleanup
+                let is_html = content_type
+                    .as_deref()
+                    .is_some_and(|ct| ct.starts_with("text/html"));
+                // The trailing spaces that mark soft line breaks of format=flowed text
+                // wouldn't survive the cleanup, so its paragraphs are joined first
+                let flowed = msg
+                    .text_part(0)
+                    .and_then(|part| part.content_type())
+                    .filter(|ct| {
+                        !is_html
+                            && ct
+                                .attribute("format")
+                                .is_some_and(|format| format.eq_ignore_ascii_case("flowed"))
+                    });
+                let body = match flowed {
+                    Some(ct) => {
+                        debug!("Joining the soft line breaks of format=flowed text");
+                        let delete_space = ct
+                            .attribute("delsp")
+                            .is_some_and(|delsp| delsp.eq_ignore_ascii_case("yes"));
+                        unflow_text(&body, delete_space).into()
+                    }
+                    None => body,
+                };
+                let body = if self.config.strip_quotes && !is_html {
+                    strip_quotes(&body).into()
+                } else {
+                    body
+                };
+                let cleaned_body = if self.config.no_whitespace_cleanup {
+                    body.replace("\r\n", "\n").trim_matches('\n').to_string()
+                } else {
+                    clean_body_whitespace(&body, is_html)
+                };
+
+                // Date, subject and extra headers are shown above the body
+                let mut header_lines: Vec<String> = date_line.into_iter().collect();
+                // Alerting tools often repeat the subject as the whole body, show that text once
+                let cleaned_body = if !subject.is_empty() && subject.contains(cleaned_body.as_str())
+                {
+                    debug!("Body repeats the subject, forwarding it once");
+                    subject.clone()
+                } else {
+                    if !subject.is_empty() {
+                        header_lines.push(format!("Subject: {}", subject));
+                    }
+                    cleaned_body
+                };
+                header_lines.extend(extra_headers);
+
+                return ExtractedEmail {
+                    subject,
+                    message_id,
+                    requested_parse_mode,
+                    header_from,
+                    header_to,
+                    from_name,
+                    date,
+                    header_lines,
+                    body: cleaned_body,
+                    content_type,
+                    attachments,
+                };
+            }
+
+            // Fallback: return empty body
+            let mut header_lines: Vec<String> = date_line.into_iter().collect();
+            if !subject.is_empty() {
+                header_lines.push(format!("Subject: {}", subject));
+            }
+            header_lines.extend(extra_headers);
+
+            ExtractedEmail {
+                subject,
+                message_id,
+                requested_parse_mode,
+                header_from,
+                header_to,
+                from_name,
+                date,
+                header_lines,
+                body: String::new(),
+                content_type,
+                attachments,
+            }
+        } else {
+            warn!("Failed to parse email message");
+            // Fallback to string conversion
+            ExtractedEmail {
+                subject: String::new(),
+                message_id: None,
+                requested_parse_mode: None,
+                header_from: None,
+                header_to: None,
+                from_name: None,
+                date: None,
+                header_lines: Vec::new(),
+                body: String::from_utf8_lossy(email_data).to_string(),
+                content_type: None,
+                attachments: Vec::new(),
+            }
+        }
+    }
+
+    // Picks the forum topic for a sender: first matching --sender-topic, then a topic
+    // created for the sender with --auto-create-topics. None posts to the general topic.
+    async fn resolve_sender_topic(&self, chat_id: &str, sender: Option<&str>) -> Option<i64> {
+        let sender = sender.filter(|sender| !sender.is_empty())?;
+
+        if let Some(topic) = self
+            .config
+            .sender_topics
+            .iter()
+            .find(|topic| wildcard_match(&topic.pattern, sender))
+        {
+            return Some(topic.thread_id);
+        }
+
+        if !self.config.auto_create_topics {
+            return None;
+        }
+        if self.config.dry_run {
+            info!(
+                "Dry run: would create a forum topic for {} in chat {}",
+                sender, chat_id
+            );
+            return None;
+        }
+
+        // Concurrent mail from a new sender waits for the one topic being created, without
+        // holding up other senders. A failed creation leaves the cell empty for a retry.
+        let key = (chat_id.to_string(), sender.to_lowercase());
+        let topic = Arc::clone(
+            self.config
+                .created_topics
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_default(),
+        );
+        let created = topic
+            .get_or_try_init(|| async {
+                let thread_id = self.create_forum_topic(chat_id, sender).await?;
+                info!("Created topic {} for sender {}", thread_id, sender);
+                if let Some(path) = &self.config.topics_file {
+                    if let Err(e) = save_created_topic(path, &key, thread_id).await {
+                        warn!("Failed to save the topic of sender {}: {:#}", sender, e);
+                    }
+                }
+                anyhow::Ok(thread_id)
+            })
+            .await;
+        match created {
+            Ok(thread_id) => Some(*thread_id),
+            Err(e) => {
+                warn!("Failed to create topic for sender {}: {}", sender, e);
+                None
+            }
+        }
+    }
+
+    async fn create_forum_topic(&self, chat_id: &str, name: &str) -> Result<i64> {
+        // Topic names are limited to 128 characters
+        let name: String = name.chars().take(128).collect();
+        let url = telegram_method_url(
+            &self.config.api_base_url,
+            &self.config.telegram_token(),
+            "createForumTopic",
+        )?;
+
+        let form = [("chat_id", chat_id), ("name", name.as_str())];
+        let response: TelegramResponse<ForumTopic> = self
+            .send_telegram_request(chat_id, || self.http_client.post(&url).form(&form))
+            .await
+            .context("Failed to call createForumTopic on Telegram")?
+            .json()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Invalid createForumTopic response from Telegram")?;
+
+        match response.result {
+            Some(topic) if response.ok => Ok(topic.message_thread_id),
+            _ => Err(anyhow::anyhow!(
+                "Telegram API error: {}",
+                response.description.unwrap_or_default()
+            )),
+        }
+    }
+
+    // Extracts a received email and forwards it to Telegram, only to `only_chats` of the
+    // recipients' chats when given. Returns the first failed send with the chats that didn't
+    // get the message; emails that aren't forwarded on purpose (no route, flood limit)
+    // succeed.
+    async fn forward_message(
+        &self,
+        data: &[u8],
+        mail_from: Option<&str>,
+        rcpt_to: &[String],
+        only_chats: Option<&[(String, Option<i64>)]>,
+    ) -> Result<(), FailedForward> {
+        // Process the received message - decode as UTF-8
+        info!("Received email message: {} bytes", data.len());
+
+        // Use mail-parser which handles all encodings automatically
+        let mut email = self.extract_text_from_email(data);
+
+        // Relays retry deliveries they didn't see acknowledged, the copy is accepted and
+        // dropped. The recipients are part of the key, because a message may also be
+        // split across transactions by recipient.
+        let dedupe_key = email
+            .message_id
+            .as_ref()
+            .filter(|_| !self.config.dedupe_ttl.is_zero())
+            .map(|message_id| {
+                let mut recipients = rcpt_to.to_vec();
+                recipients.sort();
+                format!("{}\n{}", message_id, recipients.join(","))
+            });
+        if let Some(key) = &dedupe_key {
+            if self.config.is_duplicate(key) {
+                info!(
+                    "Duplicate of message {} forwarded within the last {}s, skipped",
+                    email.message_id.as_deref().unwrap_or_default(),
+                    self.config.dedupe_ttl.as_secs()
+                );
+                return Ok(());
+            }
+        }
+
+        // Malformed mail can carry NUL and other control characters, which Telegram rejects
+        let body = strip_control_chars(&email.body);
+        if body.len() != email.body.len() {
+            debug!("Removed control characters from the email body");
+        }
+        email.body = body;
+        email.subject = strip_control_chars(&email.subject);
+        for line in &mut email.header_lines {
+            *line = strip_control_chars(line);
+        }
+
+        // Filtered mail is accepted like any other, it just isn't forwarded
+        if let Some(reason) = self.config.filter_reason(mail_from, &email.subject) {
+            info!("Message filtered, not forwarded: {}", reason);
+            Metrics::increment(&self.config.metrics.emails_filtered);
+            return Ok(());
+        }
+
+        // Resolve the destination chats from the recipients, each chat gets the message once
+        let mut chats: Vec<(ChatTarget, Vec<&str>)> = Vec::new();
+        for recipient in rcpt_to {
+            let targets = self.config.resolve_chats(recipient);
+            if targets.is_empty() {
+                info!(
+                    "No route matches recipient {} and no default chat is set, recipient skipped",
+                    recipient
+                );
+            }
+            for target in targets {
+                match chats.iter_mut().find(|(chat, _)| *chat == target) {
+                    Some((_, recipients)) => recipients.push(recipient),
+                    None => chats.push((target, vec![recipient.as_str()])),
+                }
+            }
+        }
+        // A spooled message is retried only to the chats that didn't get it
+        if let Some(only_chats) = only_chats {
+            chats.retain(|(target, _)| {
+                only_chats.iter().any(|(chat_id, thread_id)| {
+                    chat_id == target.chat_id && *thread_id == target.thread_id
+                })
+            });
+        }
+        if chats.is_empty() {
+            info!("No recipient has a destination chat, message skipped");
+            return Ok(());
+        }
+
+        if !self.config.transforms.is_empty() {
+            email.body = apply_transforms(email.body, &self.config.transforms);
+        }
+
+        // The raw email is uploaded after the email's own attachments
+        let attachment_count = email.attachments.len();
+        let attach_raw = match self.config.attach_eml {
+            Some(AttachEml::Always) => true,
+            Some(AttachEml::Empty) => email.body.is_empty(),
+            None => false,
+        };
+        if attach_raw {
+            email.attachments.push(Attachment {
+                filename: "message.eml".to_string(),
+                content_type: Some("message/rfc822".to_string()),
+                data: data.to_vec(),
+            });
+        }
+
+        if email.body.is_empty() {
+            if let Some(placeholder) = &self.config.empty_placeholder {
+                info!("Email has no text, forwarding placeholder");
+                let from = mail_from.unwrap_or("unknown sender");
+                email.body = placeholder.replace("{from}", from);
+                email.content_type = None;
+            } else if attachment_count > 0 {
+                // Scanners and the like send files without a body, the notice shows who sent
+                // them along with the uploads
+                info!("Email has no text, forwarding a notice with its attachments");
+                email.body = format!("📎 Email with {} attachment(s)", attachment_count);
+                email.content_type = None;
+            }
+        }
+        let content_type = email.content_type.clone();
+        let is_html_body = content_type
+            .as_deref()
+            .is_some_and(|ct| ct.starts_with("text/html"));
+
+        // Senders may pick the parse mode of their message with --allow-parse-mode-header
+        let requested_mode = email
+            .requested_parse_mode
+            .as_deref()
+            .filter(|_| self.config.allow_parse_mode_header)
+            .and_then(|value| {
+                let mode = parse_mode_from_header(value);
+                if mode.is_none() {
+                    warn!("Ignoring unsupported X-Telegram-Parse-Mode: {}", value);
+                }
+                mode
+            });
+
+        // Routing uses the envelope, the message may show the headers instead
+        let envelope_to = rcpt_to.join(", ");
+        let envelope_to = (!rcpt_to.is_empty()).then_some(envelope_to.as_str());
+        // The envelope sender is often a bounce address, the display name tells who sent it
+        let envelope_from = match (email.from_name.as_deref(), mail_from) {
+            (Some(name), Some(address)) => Some(format!("{} <{}>", name, address)),
+            (_, address) => address.map(str::to_string),
+        };
+        let (shown_from, shown_to) = if self.config.prefer_header_addresses {
+            (
+                email.header_from.as_deref().or(mail_from),
+                email.header_to.as_deref().or(envelope_to),
+            )
+        } else {
+            (envelope_from.as_deref(), envelope_to)
+        };
+
+        let chunk_label = self.config.chunk_header.then(|| {
+            chunk_label(
+                &email.subject,
+                email
+                    .message_id
+                    .as_deref()
+                    .map(str::as_bytes)
+                    .unwrap_or(data),
+            )
+        });
+
+        // Text of the message and its parse mode, None when there's nothing but attachments
+        let mut text_message = None;
+
+        // Plain text is shown literally with the configured parse mode, --linkify only
+        // applies with HTML
+        let plain_body = |body: &str| {
+            let mode = self.config.parse_mode.api_name();
+            if self.config.linkify && mode == Some("HTML") {
+                (linkify_plain_text(body), mode)
+            } else {
+                (escape_for_parse_mode(body, mode), mode)
+            }
+        };
+
+        if !email.body.is_empty() {
+            // Determine parse_mode based on Content-Type and convert HTML if needed
+            let (processed_body, parse_mode) = if let Some(mode) = requested_mode {
+                // The sender wrote the body in the requested markup, it's sent as is
+                match mode {
+                    Some("HTML") => (self.convert_html_to_telegram(&email.body), mode),
+                    _ if is_html_body => (
+                        html_to_plain_text(&self.convert_html_to_telegram(&email.body)),
+                        mode,
+                    ),
+                    _ => (email.body.clone(), mode),
+                }
+            } else if let Some(ct) = &content_type {
+                if ct.starts_with("text/html") {
+                    debug!("Converting HTML to Telegram-compatible format");
+                    let converted = self.convert_html_to_telegram(&email.body);
+                    (converted, Some("HTML"))
+                } else {
+                    plain_body(&email.body)
+                }
+            } else {
+                plain_body(&email.body)
+            };
+
+            // Header values and addresses are plain text and must not be parsed as markup.
+            // Addresses may contain '<', '>' or '&' (e.g. quoted local parts).
+            let escape = |text: &str| escape_for_parse_mode(text, parse_mode);
+            let headers: Vec<String> = email.header_lines.iter().map(|line| escape(line)).collect();
+            let telegram_message = render_template(
+                &self.config.template,
+                &[
+                    ("from", &escape(shown_from.unwrap_or_default())),
+                    ("to", &escape(shown_to.unwrap_or_default())),
+                    ("subject", &escape(&email.subject)),
+                    ("date", &escape(email.date.as_deref().unwrap_or_default())),
+                    ("headers", &headers.join("\n")),
+                    ("body", &processed_body),
+                ],
+            );
+
+            if let Some(mode) = parse_mode {
+                debug!(
+                    "Detected Content-Type: {}, using parse_mode: {}",
+                    content_type.as_deref().unwrap_or("none"),
+                    mode
+                );
+            }
+
+            let message_bytes = telegram_message.len();
+            debug!(
+                "Message to send: {} bytes ({} characters)",
+                message_bytes,
+                telegram_message.chars().count()
+            );
+
+            text_message = Some((telegram_message, parse_mode));
+        }
+
+        let mut failure: Option<FailedForward> = None;
+        for (target, recipients) in chats {
+            if let Err(e) = self
+                .deliver_to_chat(
+                    target,
+                    &recipients,
+                    mail_from,
+                    &email,
+                    text_message
+                        .as_ref()
+                        .map(|(text, mode)| (text.as_str(), *mode)),
+                    chunk_label.as_deref(),
+                )
+                .await
+            {
+                let failed = failure.get_or_insert_with(|| FailedForward {
+                    error: e,
+                    recipients: Vec::new(),
+                    chats: Vec::new(),
+                });
+                // A recipient sent to several default chats may have failed before
+                for recipient in recipients {
+                    if !failed.recipients.iter().any(|r| r == recipient) {
+                        failed.recipients.push(recipient.to_string());
+                    }
+                }
+                failed
+                    .chats
+                    .push((target.chat_id.to_string(), target.thread_id));
+            }
+        }
+
+        match failure {
+            Some(failed) => Err(failed),
+            None => {
+                // A failed message isn't remembered, so that a retry of it is forwarded
+                if let Some(key) = dedupe_key {
+                    self.config.remember_forwarded(key);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Writes a message that couldn't be forwarded to --spool-dir
+    async fn spool_message(
+        &self,
+        data: &[u8],
+        mail_from: Option<&str>,
+        failed: &FailedForward,
+    ) -> Result<()> {
+        let spool = self.config.spool.as_ref().context("No spool directory")?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let content = spool_file_content(data, mail_from, failed, now.as_secs());
+
+        let used = spool.size().await?;
+        if used + content.len() as u64 > spool.max_size {
+            return Err(anyhow::anyhow!(
+                "spool is full ({} of {} bytes used)",
+                used,
+                spool.max_size
+            ));
+        }
+
+        // Named by time, so that messages are retried in order
+        let sequence = SPOOL_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let path = spool
+            .dir
+            .join(format!("{}-{}.eml", now.as_millis(), sequence));
+        write_spool_file(&path, &content).await?;
+        warn!(
+            "Message for {} spooled to {} for a later retry",
+            failed.recipients.join(", "),
+            path.display()
+        );
+        Ok(())
+    }
+
+    // Reports the messages dropped in flood windows that are over to their chats
+    async fn flush_flood_notices(&self) {
+        for (chat_id, thread_id, suppressed) in self.config.take_expired_flood_windows() {
+            let target = ChatTarget {
+                chat_id: &chat_id,
+                thread_id,
+            };
+            let notice = self.config.flood_notice(suppressed);
+            if let Err(e) = self
+                .send_to_telegram(target, &notice, None, None, None)
+                .await
+            {
+                warn!("Failed to send flood notice to Telegram: {}", e);
+            }
+        }
+    }
+
+    // Re-sends the spooled messages, oldest first. Stops at the first temporary failure,
+    // Telegram is most likely still unavailable.
+    async fn retry_spool(&self) -> Result<()> {
+        let Some(spool) = &self.config.spool else {
+            return Ok(());
+        };
+        for path in spool.messages().await? {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let retried = self
+                .retry_spooled_message(spool, &path)
+                .instrument(tracing::info_span!("spool", file = %name))
+                .await;
+            match retried {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => warn!("Failed to retry spooled message {}: {:#}", name, e),
+            }
+        }
+        Ok(())
+    }
+
+    // Retries one spooled message, returning false when it failed again for a reason that
+    // may go away. Delivered, rejected and expired messages are deleted.
+    async fn retry_spooled_message(&self, spool: &Spool, path: &Path) -> Result<bool> {
+        let content = tokio::fs::read(path).await?;
+        let Some(spooled) = parse_spooled_message(&content) else {
+            error!("Spooled message has no envelope, deleting it");
+            tokio::fs::remove_file(path).await?;
+            return Ok(true);
+        };
+
+        // Counted from the first failure, a rewritten spool file keeps that time
+        let age = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(Duration::from_secs(spooled.queued));
+        if age > spool.max_age {
+            error!(
+                "Giving up on spooled message after {}s, deleting it",
+                age.as_secs()
+            );
+            tokio::fs::remove_file(path).await?;
+            return Ok(true);
+        }
+
+        // A spool file without X-Spool-Chat lines is retried to every chat
+        let only_chats = Some(spooled.chats.as_slice()).filter(|chats| !chats.is_empty());
+        match self
+            .forward_message(
+                spooled.data,
+                spooled.mail_from.as_deref(),
+                &spooled.recipients,
+                only_chats,
+            )
+            .await
+        {
+            Ok(()) => {
+                info!("Spooled message delivered");
+                tokio::fs::remove_file(path).await?;
+                Ok(true)
+            }
+            Err(failed) if failed.error.is_permanent() => {
+                error!(
+                    "Spooled message rejected by Telegram, deleting it: {:#}",
+                    failed.error
+                );
+                tokio::fs::remove_file(path).await?;
+                Ok(true)
+            }
+            Err(failed) => {
+                warn!("Spooled message still can't be sent: {:#}", failed.error);
+                // Chats that got the message meanwhile aren't sent it again. The file is
+                // replaced in one rename, so a failed write leaves the old one.
+                if failed.chats != spooled.chats {
+                    let content = spool_file_content(
+                        spooled.data,
+                        spooled.mail_from.as_deref(),
+                        &failed,
+                        spooled.queued,
+                    );
+                    write_spool_file(path, &content).await?;
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    // Sends a prepared message and the attachments to one chat, applying the flood limit
+    // and sender topics of that chat. Returns the first failed send.
+    async fn deliver_to_chat(
+        &self,
+        destination: ChatTarget<'_>,
+        recipients: &[&str],
+        mail_from: Option<&str>,
+        email: &ExtractedEmail,
+        text_message: Option<(&str, Option<&str>)>,
+        chunk_label: Option<&str>,
+    ) -> Result<(), ForwardError> {
+        let chat_id = destination.chat_id;
+
+        // Flood protection: drop messages over the limit and report how many were dropped
+        // with the first message of the next window
+        let Some(suppressed) = self.config.admit_message(destination) else {
+            warn!(
+                "Flood limit reached for chat {}, message suppressed",
+                chat_id
+            );
+            return Ok(());
+        };
+        if suppressed > 0 {
+            let notice = self.config.flood_notice(suppressed);
+            if let Err(e) = self
+                .send_to_telegram(destination, &notice, None, None, None)
+                .await
+            {
+                warn!("Failed to send flood notice to Telegram: {}", e);
+            }
+        }
+
+        // A sender topic takes precedence over the topic of the route or --thread-id
+        let target = ChatTarget {
+            chat_id,
+            thread_id: self
+                .resolve_sender_topic(chat_id, mail_from)
+                .await
+                .or(destination.thread_id),
+        };
+
+        let reply_markup = build_reply_markup(
+            &self.config.buttons,
+            &[
+                ("subject", &email.subject),
+                ("from", mail_from.unwrap_or_default()),
+                ("to", &recipients.join(", ")),
+            ],
+        );
+
+        // The first photo carries the text as its caption when the text is short enough
+        let mut photo_with_caption = None;
+        let mut failure = None;
+
+        if let Some((telegram_message, parse_mode)) = text_message {
+            let photo_index = email.attachments.iter().position(Attachment::is_photo);
+            match photo_index {
+                Some(index) if message_len(telegram_message, parse_mode) <= MAX_CAPTION_LENGTH => {
+                    let photo = &email.attachments[index];
+                    info!("Sending message as caption of {}", photo.filename);
+                    match self
+                        .send_attachment(
+                            target,
+                            photo,
+                            Some(telegram_message),
+                            parse_mode,
+                            reply_markup.as_deref(),
+                        )
+                        .await
+                    {
+                        Ok(()) => info!(
+                            "Message forwarded to Telegram chat {} successfully",
+                            chat_id
+                        ),
+                        Err(e) => {
+                            error!("Failed to send to Telegram chat {}: {:#}", chat_id, e);
+                            failure = Some(e);
+                        }
+                    }
+                    photo_with_caption = Some(index);
+                }
+                _ => {
+                    if let Err(e) = self
+                        .send_to_telegram(
+                            target,
+                            telegram_message,
+                            parse_mode,
+                            reply_markup.as_deref(),
+                            chunk_label,
+                        )
+                        .await
+                    {
+                        error!("Failed to send to Telegram chat {}: {:#}", chat_id, e);
+                        failure = Some(e);
+                    } else {
+                        info!(
+                            "Message forwarded to Telegram chat {} successfully",
+                            chat_id
+                        );
+                    }
+                }
+            }
+        }
+
+        // Attachments follow the text
+        for (index, attachment) in email.attachments.iter().enumerate() {
+            if photo_with_caption == Some(index) {
+                continue;
+            }
+            if let Err(e) = self
+                .send_attachment(target, attachment, None, None, None)
+                .await
+            {
+                error!(
+                    "Failed to send attachment {} to Telegram: {:#}",
+                    attachment.filename, e
+                );
+                failure.get_or_insert(e);
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => {
+                Metrics::increment(&self.config.metrics.messages_forwarded);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SmtpSession {
+    pub async fn handle(&mut self) -> Result<()> {
+        // Clients must wait for the greeting, spambots often don't
+        if self.config.greeting_delay > 0 {
+            let delay = Duration::from_millis(self.config.greeting_delay);
+            let mut early = [0u8; 1];
+            match tokio::time::timeout(delay, self.stream.read(&mut early)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(_)) => {
+                    self.send_response(Response::new(
+                        554,
+                        0,
+                        0,
+                        0,
+                        "Protocol violation: data sent before greeting".to_string(),
+                    ))
+                    .await?;
+                    self.stream.shutdown().await.ok();
+                    return Err(anyhow::anyhow!("Client sent data before the greeting"));
+                }
+                Ok(Err(e)) => return Err(e).context("Failed to read from stream"),
+                Err(_) => {}
+            }
+        }
+
+        self.send_greeting().await?;
+
+        let mut mail_from: Option<String> = None;
+        let mut rcpt_to: Vec<String> = Vec::new();
+        let mut in_data = false;
+        // Set once DATA goes over --max-message-size, the rest is read but not stored
+        let mut data_too_large = false;
+        // Set after STARTTLS: the client must introduce itself again
+        let mut helo_required = false;
+        // Set while BDAT chunks of an unfinished message are buffered
+        let mut in_bdat = false;
+
+        loop {
+            // Handle authentication state
+            if !matches!(self.auth_state, AuthState::None) {
+                let line_bytes = self.read_line_bytes().await?;
+                let line =
+                    String::from_utf8_lossy(&line_bytes[..line_bytes.len().saturating_sub(2)]);
+                let line = line.trim();
+
+                // RFC 4954: a single "*" cancels the exchange
+                if line == "*" {
+                    self.auth_state = AuthState::None;
+                    self.send_response(Response::new(
+                        501,
+                        0,
+                        0,
+                        0,
+                        "Authentication cancelled".to_string(),
+                    ))
+                    .await?;
+                    continue;
+                }
+
+                match std::mem::replace(&mut self.auth_state, AuthState::None) {
+                    AuthState::LoginWaitingUsername => {
+                        // Decode base64 username
+                        let username = decode_auth_text(line);
+                        self.send_auth_challenge("UGFzc3dvcmQ6").await?; // "Password:" in base64
+                        self.auth_state = AuthState::LoginWaitingPassword(username);
+                    }
+                    AuthState::LoginWaitingPassword(username) => {
+                        // Decode base64 password
+                        let password = decode_auth_text(line);
+                        self.finish_auth("LOGIN", &username, &password).await?;
+                    }
+                    AuthState::PlainWaitingData => {
+                        self.finish_plain_auth(line).await?;
+                    }
+                    AuthState::None => {}
+                }
+                continue;
+            }
+
+            if in_data {
+                // In DATA mode, read bytes directly to preserve UTF-8 encoding. Commands
+                // aren't parsed here, so a "RSET" or "QUIT" line is message content and
+                // only the terminator leaves this state.
+                let line_bytes = match self.read_line_bytes().await {
+                    Ok(line_bytes) => line_bytes,
+                    Err(e) => {
+                        // A message is only forwarded after its terminator, a partial one
+                        // is dropped with the connection
+                        let received = self.buffer.len();
+                        self.buffer.clear();
+                        if e.is::<ClientDisconnected>() {
+                            warn!(
+                                "Client disconnected after {} bytes of DATA, partial message not forwarded",
+                                received
+                            );
+                            return Ok(());
+                        }
+                        warn!(
+                            "Connection lost during DATA, discarding partial message of {} bytes",
+                            received
+                        );
+                        return Err(e);
+                    }
+                };
+
+                // Check if this is the end of DATA (single dot on a line). The terminator is
+                // <CRLF>.<CRLF> where the first CRLF ends the previous line (RFC 5321 4.1.1.4),
+                // so clients must add a CRLF to bodies that lack one. A line like "body.\r\n"
+                // is regular content ending in a period and must not finish the message.
+                if line_bytes == b".\r\n" {
+                    // End of DATA
+                    in_data = false;
+
+                    if data_too_large {
+                        warn!(
+                            "Rejected email over the maximum size of {} bytes",
+                            self.config.max_message_size
+                        );
+                        data_too_large = false;
+                        self.buffer.clear();
+                        mail_from = None;
+                        rcpt_to.clear();
+                        self.send_response(Response::new(
+                            552,
+                            0,
+                            0,
+                            0,
+                            "Message size exceeds fixed maximum".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+
+                    self.deliver_message(mail_from.as_deref(), &rcpt_to).await?;
+                    mail_from = None;
+                    rcpt_to.clear();
+                } else {
+                    // If line starts with "..", remove the first dot (SMTP escaping)
+                    let processed_bytes = if line_bytes.len() >= 3
+                        && line_bytes[0] == b'.'
+                        && line_bytes[1] == b'.'
+                        && line_bytes[line_bytes.len() - 2] == b'\r'
+                        && line_bytes[line_bytes.len() - 1] == b'\n'
+                    {
+                        // Remove the first dot and keep the rest (including \r\n)
+                        line_bytes[1..].to_vec()
+                    } else {
+                        // Keep the line as is (including \r\n)
+                        line_bytes
+                    };
+
+                    // Continue reading data - store bytes directly
+                    if self.buffer.len() + processed_bytes.len() > self.config.max_message_size {
+                        // Keep consuming until the terminator so the session stays in sync
+                        data_too_large = true;
+                        self.buffer = Vec::new();
+                    }
+                    if !data_too_large {
+                        self.buffer.extend_from_slice(&processed_bytes);
+                    }
+                }
+                continue;
+            }
+
+            // Parse SMTP command
+            // Request::parse requires a complete line with \r\n, so we read bytes directly
+            let line_bytes = self.read_line_bytes().await?;
+            let line_str =
+                String::from_utf8_lossy(&line_bytes[..line_bytes.len().saturating_sub(2)]);
+            let line_upper = line_str.trim().to_uppercase();
+            // AUTH arguments are credentials and stay out of the log
+            match line_upper.strip_prefix("AUTH ") {
+                Some(rest) => debug!(
+                    "SMTP command: AUTH {}",
+                    rest.split_whitespace().next().unwrap_or_default()
+                ),
+                None => debug!("SMTP command: {}", line_str.trim()),
+            }
+
+            // Without --auth-user/--auth-pass, AUTH isn't offered and is refused
+            if (line_upper == "AUTH" || line_upper.starts_with("AUTH "))
+                && self.config.auth_credentials.is_none()
+            {
+                self.send_response(auth_not_enabled()).await?;
+                continue;
+            }
+
+            // Check if it's an AUTH command before parsing
+            if line_upper.starts_with("AUTH ") {
+                let parts: Vec<&str> = line_str.split_whitespace().collect();
+                if parts.len() >= 2 {
+                    let mechanism = parts[1].to_uppercase();
+                    let initial_response = if parts.len() >= 3 {
+                        Some(parts[2..].join(" "))
+                    } else {
+                        None
+                    };
+
+                    match mechanism.as_str() {
+                        "LOGIN" => {
+                            if let Some(initial) = initial_response {
+                                // Username provided in initial response
+                                let username = decode_auth_text(&initial);
+                                self.send_auth_challenge("UGFzc3dvcmQ6").await?; // "Password:" in base64
+                                self.auth_state = AuthState::LoginWaitingPassword(username);
+                            } else {
+                                // Request username
+                                self.send_auth_challenge("VXNlcm5hbWU6").await?; // "Username:" in base64
+                                self.auth_state = AuthState::LoginWaitingUsername;
+                            }
+                            continue;
+                        }
+                        "PLAIN" => {
+                            if let Some(initial) = initial_response {
+                                // PLAIN data provided in initial response
+                                self.finish_plain_auth(&initial).await?;
+                            } else {
+                                // Request PLAIN data
+                                self.send_auth_challenge("").await?;
+                                self.auth_state = AuthState::PlainWaitingData;
+                            }
+                            continue;
+                        }
+                        _ => {
+                            self.send_response(Response::new(
+                                504,
+                                0,
+                                0,
+                                0,
+                                format!("Unsupported authentication method: {}", mechanism),
+                            ))
+                            .await?;
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            let mut iter = line_bytes.iter();
+            // RFC 5321 4.2.4: reply to unrecognized or malformed commands and keep the session
+            let request = match Request::parse(&mut iter) {
+                Ok(request) => request,
+                Err(e) => {
+                    info!(
+                        "Failed to parse SMTP request {:?}: {:?}",
+                        line_str.trim(),
+                        e
+                    );
+                    self.send_response(Response::new(500, 5, 5, 2, "Syntax error".to_string()))
+                        .await?;
+                    continue;
+                }
+            };
+
+            match request {
+                Request::Helo { host } => {
+                    helo_required = false;
+                    self.send_response(Response::new(
+                        250,
+                        0,
+                        0,
+                        0,
+                        format!("Hello {}", host.into_owned()),
+                    ))
+                    .await?;
+                }
+                Request::Ehlo { host } => {
+                    // Send multi-line response in correct SMTP format
+                    let host_str = host.into_owned();
+                    helo_required = false;
+                    let mut lines = vec![format!("{} Hello {}", self.config.hostname, host_str)];
+                    lines.extend(self.ehlo_capabilities());
+                    // Format: continuation lines with dash (250-), last line without dash (250 )
+                    let last = lines.len() - 1;
+                    let response: String = lines
+                        .iter()
+                        .enumerate()
+                        .map(|(i, line)| {
+                            format!("250{}{}\r\n", if i == last { ' ' } else { '-' }, line)
+                        })
+                        .collect();
+                    debug!("Sending EHLO response: {:?}", response);
+                    self.stream
+                        .write_all(response.as_bytes())
+                        .await
+                        .context("Failed to write EHLO response")?;
+                    self.stream
+                        .flush()
+                        .await
+                        .context("Failed to flush EHLO response")?;
+                    debug!("EHLO response sent and flushed");
+                }
+                Request::Lhlo { host } => {
+                    helo_required = false;
+                    self.send_response(Response::new(
+                        250,
+                        0,
+                        0,
+                        0,
+                        format!("Hello {}", host.into_owned()),
+                    ))
+                    .await?;
+                }
+                Request::Mail { from } => {
+                    if helo_required {
+                        self.send_response(Response::new(
+                            503,
+                            0,
+                            0,
+                            0,
+                            "Send EHLO/HELO first".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    if self.config.auth_credentials.is_some() && !self.authenticated {
+                        self.send_response(Response::new(
+                            530,
+                            0,
+                            0,
+                            0,
+                            "Authentication required".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    // SIZE=n from the client lets oversized mail be refused before DATA
+                    if from.size > self.config.max_message_size {
+                        self.send_response(Response::new(
+                            552,
+                            0,
+                            0,
+                            0,
+                            "Message size exceeds fixed maximum".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    mail_from = Some(from.address.into_owned());
+                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
+                        .await?;
+                }
+                Request::Rcpt { to } => {
+                    // RFC 5321 4.5.3.1.10: the client sends the rest in another transaction
+                    if rcpt_to.len() >= self.config.max_recipients {
+                        self.send_response(Response::new(
+                            452,
+                            0,
+                            0,
+                            0,
+                            "Too many recipients".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    rcpt_to.push(to.address.into_owned());
+                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
+                        .await?;
+                }
+                Request::Data => {
+                    // RFC 3030: DATA can't complete a message started with BDAT
+                    if in_bdat {
+                        self.send_response(Response::new(
+                            503,
+                            0,
+                            0,
+                            0,
+                            "BDAT in progress, send RSET first".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    if mail_from.is_none() || rcpt_to.is_empty() {
+                        self.send_response(Response::new(
+                            503,
+                            0,
+                            0,
+                            0,
+                            "Need MAIL and RCPT first".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+                    in_data = true;
+                    self.buffer.clear();
+                    self.send_response(Response::new(
+                        354,
+                        0,
+                        0,
+                        0,
+                        "End data with <CR><LF>.<CR><LF>".to_string(),
+                    ))
+                    .await?;
+                }
+                Request::Rset => {
+                    mail_from = None;
+                    rcpt_to.clear();
+                    self.buffer.clear();
+                    in_bdat = false;
+                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
+                        .await?;
+                }
+                Request::Quit => {
+                    self.send_response(Response::new(221, 0, 0, 0, "Bye".to_string()))
+                        .await?;
+                    // Sends TLS close_notify on encrypted connections
+                    let _ = self.stream.shutdown().await;
+                    break;
+                }
+                Request::Noop { .. } => {
+                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
+                        .await?;
+                }
+                Request::Vrfy { .. } => {
+                    self.send_response(Response::new(
+                        502,
+                        0,
+                        0,
+                        0,
+                        "Command not implemented".to_string(),
+                    ))
+                    .await?;
+                }
+                Request::Expn { .. } => {
+                    self.send_response(Response::new(
+                        502,
+                        0,
+                        0,
+                        0,
+                        "Command not implemented".to_string(),
+                    ))
+                    .await?;
+                }
+                Request::Help { .. } => {
+                    self.send_response(Response::new(214, 0, 0, 0, "Help text".to_string()))
+                        .await?;
+                }
+                Request::StartTls => {
+                    let Some(acceptor) = self.config.tls_acceptor.clone() else {
+                        self.send_response(Response::new(
+                            502,
+                            0,
+                            0,
+                            0,
+                            "TLS not supported".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    };
+                    if self.stream.get_ref().is_tls() {
+                        self.send_response(Response::new(
+                            503,
+                            0,
+                            0,
+                            0,
+                            "TLS already active".to_string(),
+                        ))
+                        .await?;
+                        continue;
+                    }
+
+                    self.send_response(Response::new(
+                        220,
+                        0,
+                        0,
+                        0,
+                        "Ready to start TLS".to_string(),
+                    ))
+                    .await?;
+                    self.upgrade_to_tls(acceptor).await?;
+                    info!("TLS established");
+
+                    // RFC 3207: discard all knowledge obtained from the client before TLS
+                    mail_from = None;
+                    rcpt_to.clear();
+                    self.buffer.clear();
+                    in_bdat = false;
+                    self.auth_state = AuthState::None;
+                    self.authenticated = false;
+                    helo_required = true;
+                }
+                Request::Auth { .. } => {
+                    // AUTH command is handled above before parsing
+                    // This should not be reached, but handle it anyway
+                    self.send_response(Response::new(
+                        502,
+                        0,
+                        0,
+                        0,
+                        "Auth not supported".to_string(),
+                    ))
+                    .await?;
+                }
+                Request::Bdat {
+                    chunk_size,
+                    is_last,
+                } => {
+                    // The chunk follows the command whether or not it is accepted, so it is
+                    // always consumed to keep the session in sync (RFC 3030 section 2).
+                    // The size is compared to the room left, since a sum with the buffer
+                    // length could overflow for a huge size.
+                    let room = self
+                        .config
+                        .max_message_size
+                        .saturating_sub(self.buffer.len());
+                    let refusal = if mail_from.is_none() || rcpt_to.is_empty() {
+                        Some(Response::new(
+                            503,
+                            0,
+                            0,
+                            0,
+                            "Need MAIL and RCPT first".to_string(),
+                        ))
+                    } else if chunk_size > room {
+                        warn!(
+                            "Rejected email over the maximum size of {} bytes",
+                            self.config.max_message_size
+                        );
+                        Some(Response::new(
+                            552,
+                            0,
+                            0,
+                            0,
+                            "Message size exceeds fixed maximum".to_string(),
+                        ))
+                    } else {
+                        None
+                    };
+
+                    if let Err(e) = self.read_bdat_chunk(chunk_size, refusal.is_some()).await {
+                        let received = self.buffer.len();
+                        self.buffer.clear();
+                        if e.is::<ClientDisconnected>() {
+                            warn!(
+                                "Client disconnected after {} bytes of BDAT data ({:#}), partial message not forwarded",
+                                received, e
+                            );
+                            return Ok(());
+                        }
+                        warn!(
+                            "Connection lost during BDAT, discarding partial message of {} bytes",
+                            received
+                        );
+                        return Err(e);
+                    }
+
+                    if let Some(response) = refusal {
+                        // An oversized message ends the transaction, like at the DATA terminator
+                        if response.code == 552 {
+                            self.buffer.clear();
+                            mail_from = None;
+                            rcpt_to.clear();
+                            in_bdat = false;
+                        }
+                        self.send_response(response).await?;
+                        continue;
+                    }
+
+                    if !is_last {
+                        in_bdat = true;
+                        self.send_response(Response::new(
+                            250,
+                            0,
+                            0,
+                            0,
+                            format!("{} octets received", chunk_size),
+                        ))
+                        .await?;
+                        continue;
+                    }
+
+                    in_bdat = false;
+                    self.deliver_message(mail_from.as_deref(), &rcpt_to).await?;
+                    mail_from = None;
+                    rcpt_to.clear();
+                }
+                Request::Burl { .. } | Request::Etrn { .. } | Request::Atrn { .. } => {
+                    self.send_response(Response::new(
+                        502,
+                        0,
+                        0,
+                        0,
+                        "Command not implemented".to_string(),
+                    ))
+                    .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A message that wasn't forwarded to every chat: the first error, the chats (and topics)
+// that didn't get it and the recipients routed to them
+struct FailedForward {
+    error: ForwardError,
+    recipients: Vec<String>,
+    chats: Vec<(String, Option<i64>)>,
+}
+
+// How often flood windows are checked for a notice that is due
+const FLOOD_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often spooled messages are retried
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Envelope lines above the message in a spool file
+const SPOOL_MAIL_FROM: &str = "X-Spool-Mail-From: ";
+const SPOOL_RCPT_TO: &str = "X-Spool-Rcpt-To: ";
+const SPOOL_CHAT: &str = "X-Spool-Chat: ";
+const SPOOL_QUEUED: &str = "X-Spool-Queued: ";
+
+// Tells apart spool files written in the same millisecond
+static SPOOL_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// --spool-dir with its limits
+pub struct Spool {
+    pub dir: PathBuf,
+    pub max_age: Duration,
+    pub max_size: u64,
+}
+
+impl Spool {
+    // Spooled messages, oldest first
+    async fn messages(&self) -> Result<Vec<PathBuf>> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .context(format!("Failed to read {}", self.dir.display()))?;
+        let mut messages = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "eml") {
+                messages.push(path);
+            }
+        }
+        // The names start with the time in milliseconds, which sorts by length first
+        messages.sort_by_key(|path| {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            (name.len(), name)
+        });
+        Ok(messages)
+    }
+
+    // Total size of the spooled messages in bytes
+    async fn size(&self) -> Result<u64> {
+        let mut size = 0;
+        for path in self.messages().await? {
+            size += tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+        }
+        Ok(size)
+    }
+}
+
+// Spool file for a message: the envelope, the chats still to send to and the time of the
+// first failure in X-Spool-* lines, a blank line, then the original message
+fn spool_file_content(
+    data: &[u8],
+    mail_from: Option<&str>,
+    failed: &FailedForward,
+    queued: u64,
+) -> Vec<u8> {
+    let mut content = format!("{}{}\r\n", SPOOL_MAIL_FROM, mail_from.unwrap_or_default());
+    for recipient in &failed.recipients {
+        content.push_str(&format!("{}{}\r\n", SPOOL_RCPT_TO, recipient));
+    }
+    for (chat_id, thread_id) in &failed.chats {
+        match thread_id {
+            Some(thread_id) => {
+                content.push_str(&format!("{}{}:{}\r\n", SPOOL_CHAT, chat_id, thread_id))
+            }
+            None => content.push_str(&format!("{}{}\r\n", SPOOL_CHAT, chat_id)),
+        }
+    }
+    content.push_str(&format!("{}{}\r\n\r\n", SPOOL_QUEUED, queued));
+    let mut content = content.into_bytes();
+    content.extend_from_slice(data);
+    content
+}
+
+// Writes a spool file under a temporary name first and renames it into place, so the retry
+// task never reads a partial file and a replaced file is never lost
+async fn write_spool_file(path: &Path, content: &[u8]) -> Result<()> {
+    let partial = path.with_extension("tmp");
+    tokio::fs::write(&partial, content)
+        .await
+        .context(format!("Failed to write {}", partial.display()))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .context(format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+// Message read back from a spool file
+struct SpooledMessage<'a> {
+    mail_from: Option<String>,
+    recipients: Vec<String>,
+    // Chats still to send to, CHAT_ID[:THREAD_ID] lines; empty for every chat
+    chats: Vec<(String, Option<i64>)>,
+    // Seconds since the Unix epoch when the message was first spooled
+    queued: u64,
+    data: &'a [u8],
+}
+
+// Splits a spool file into the envelope, the chats still to send to and the original message.
+// The envelope ends at the first blank line, lines of the message itself are never read as
+// part of it.
+fn parse_spooled_message(content: &[u8]) -> Option<SpooledMessage<'_>> {
+    let mut mail_from = None;
+    let mut recipients = Vec::new();
+    let mut chats = Vec::new();
+    let mut queued = None;
+    let mut rest = content;
+    loop {
+        let end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let line = std::str::from_utf8(&rest[..end]).ok()?;
+        rest = &rest[end + 2..];
+        if line.is_empty() {
+            break;
+        } else if let Some(sender) = line.strip_prefix(SPOOL_MAIL_FROM) {
+            mail_from = Some(sender.to_string()).filter(|sender| !sender.is_empty());
+        } else if let Some(recipient) = line.strip_prefix(SPOOL_RCPT_TO) {
+            recipients.push(recipient.to_string());
+        } else if let Some(chat) = line.strip_prefix(SPOOL_CHAT) {
+            chats.push(match chat.split_once(':') {
+                Some((chat_id, thread_id)) => (chat_id.to_string(), Some(thread_id.parse().ok()?)),
+                None => (chat.to_string(), None),
+            });
+        } else if let Some(time) = line.strip_prefix(SPOOL_QUEUED) {
+            queued = Some(time.parse().ok()?);
+        } else {
+            return None;
+        }
+    }
+    (!recipients.is_empty()).then_some(SpooledMessage {
+        mail_from,
+        recipients,
+        chats,
+        queued: queued?,
+        data: rest,
+    })
+}
+
+// Sends the notices of flood windows that ended without another message to the chat, checked
+// every FLOOD_FLUSH_INTERVAL
+pub async fn flush_flood_notices(telegram: TelegramClient) {
+    let mut interval = tokio::time::interval(FLOOD_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        telegram.flush_flood_notices().await;
+    }
+}
+
+// Re-sends spooled messages every SPOOL_RETRY_INTERVAL, starting with those left over from
+// a previous run
+pub async fn retry_spooled_messages(telegram: TelegramClient) {
+    let mut interval = tokio::time::interval(SPOOL_RETRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = telegram.retry_spool().await {
+            warn!("Failed to retry spooled messages: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(chunks: &[String]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.chars().count()).collect()
+    }
+
+    #[test]
+    fn split_message_keeps_text_that_fits() {
+        assert_eq!(split_message("hello", 4096), vec!["hello"]);
+        let exact = "a".repeat(4096);
+        assert_eq!(split_message(&exact, 4096), vec![exact]);
+    }
+
+    #[test]
+    fn split_message_cuts_text_without_whitespace_at_the_limit() {
+        let text = "a".repeat(8193);
+        let chunks = split_message(&text, 4096);
+        assert_eq!(lengths(&chunks), vec![4096, 4096, 1]);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_counts_characters_not_bytes() {
+        let text = "漢字".repeat(2500);
+        let chunks = split_message(&text, 4096);
+        assert_eq!(lengths(&chunks), vec![4096, 904]);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_breaks_after_multibyte_whitespace() {
+        let text = format!("{}\u{3000}{}", "字".repeat(4000), "b".repeat(200));
+        let chunks = split_message(&text, 4096);
+        assert!(chunks[0].ends_with('\u{3000}'));
+        assert_eq!(lengths(&chunks), vec![4001, 200]);
+    }
+
+    #[test]
+    fn split_message_prefers_line_breaks_to_spaces() {
+        let text = format!(
+            "{}\n{} {}",
+            "a".repeat(3800),
+            "b".repeat(200),
+            "c".repeat(200)
+        );
+        let chunks = split_message(&text, 4096);
+        assert_eq!(chunks[0], format!("{}\n", "a".repeat(3800)));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_ignores_breaks_outside_the_window() {
+        let text = format!("{} {}", "a".repeat(100), "b".repeat(5000));
+        assert_eq!(lengths(&split_message(&text, 4096)), vec![4096, 1005]);
+    }
+
+    #[test]
+    fn split_message_keeps_escapes_together() {
+        let text = format!("{}\\.{}", "a".repeat(4095), "b".repeat(10));
+        let chunks = split_message(&text, 4096);
+        assert_eq!(chunks[0], "a".repeat(4095));
+        assert!(chunks[1].starts_with("\\."));
+    }
+
+    #[test]
+    fn split_message_counts_utf16_code_units() {
+        let text = "😀".repeat(3000);
+        let chunks = split_message(&text, 4096);
+        assert_eq!(lengths(&chunks), vec![2048, 952]);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_keeps_grapheme_clusters_whole() {
+        let thumbs = "👍🏽";
+        let text = format!("{}{}b", "a".repeat(4094), thumbs);
+        let chunks = split_message(&text, 4096);
+        assert_eq!(chunks[0], "a".repeat(4094));
+        assert_eq!(chunks[1], format!("{}b", thumbs));
+
+        let accented = format!("{}e\u{301}", "a".repeat(4095));
+        assert_eq!(split_message(&accented, 4096)[1], "e\u{301}");
+    }
+
+    // Syslog-style lines of about 80 characters each
+    fn log_lines(host: &str, count: usize) -> String {
+        (0..count)
+            .map(|i| {
+                format!(
+                    "Oct 16 03:{:02}:{:02} {} kernel: EXT4-fs warning (device sda1): ext4_dx_add_entry\n",
+                    i / 60 % 60,
+                    i % 60,
+                    host
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_message_prefers_paragraphs_to_line_breaks() {
+        let first = format!("Host: db1\n{}", log_lines("db1", 45));
+        let text = format!("{}\nHost: db2\n{}", first, log_lines("db2", 45));
+        let chunks = split_message(&text, 4096);
+        assert_eq!(chunks[0], format!("{}\n", first));
+        assert!(chunks[1].starts_with("Host: db2\n"));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_lookback_keeps_log_lines_whole() {
+        // A long stack trace line with spaces but no line break near the limit
+        let trace = format!(
+            "Traceback: {}\n",
+            "at com.example.Worker.run(Worker.java:42) ".repeat(60)
+        );
+        let text = format!(
+            "{}\n{}{}",
+            log_lines("app1", 30),
+            trace,
+            log_lines("app1", 10)
+        );
+        let chunks = split_message_with_lookback(&text, 4096, 500);
+        assert!(!chunks[0].ends_with('\n'));
+        let chunks = split_message_with_lookback(&text, 4096, 2000);
+        assert_eq!(chunks[0], format!("{}\n", log_lines("app1", 30)));
+        assert!(chunks[1].starts_with("Traceback: "));
+    }
+
+    #[test]
+    fn split_html_chunks_prefers_paragraphs() {
+        let text = escape_html(&log_lines("db1", 45));
+        let html = format!("<b>db1</b>\n{}\n<pre>{}</pre>", text, text);
+        let chunks = split_html_chunks(&html, 4096, DEFAULT_SPLIT_LOOKBACK);
+        assert_eq!(chunks[0], format!("<b>db1</b>\n{}\n", text));
+        assert!(chunks[1].starts_with("<pre>"));
+    }
+
+    #[test]
+    fn split_html_chunks_counts_emoji_as_two_code_units() {
+        let line = format!("<b>{}</b> &#128512;&amp;\n", "\u{1F600}".repeat(30));
+        let html = line.repeat(200);
+        let chunks = split_html_chunks(&html, 4096, DEFAULT_SPLIT_LOOKBACK);
+        assert!(chunks.len() > 3);
+        for chunk in &chunks {
+            assert!(html_visible_len(chunk) <= 4096);
+            assert_eq!(
+                html_visible_len(chunk),
+                telegram_len(&html_to_plain_text(chunk))
+            );
+        }
+        let joined: String = chunks
+            .iter()
+            .map(|chunk| html_to_plain_text(chunk))
+            .collect();
+        assert_eq!(joined, html_to_plain_text(&html));
+    }
+
+    #[test]
+    fn split_with_prefix_fits_prefix_in_limit() {
+        let prefix = |number: usize, total: usize| format!("[{}/{}]\n\n", number, total);
+        let text = "a".repeat(8193);
+        let chunks = split_with_prefix(&text, 4096, split_message, prefix);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("[1/3]\n\n"));
+        assert!(chunks[2].starts_with("[3/3]\n\n"));
+        assert!(lengths(&chunks).iter().all(|&len| len <= 4096));
+    }
+
+    #[test]
+    fn split_with_prefix_leaves_single_part_unprefixed() {
+        let prefix = |number: usize, total: usize| format!("[{}/{}]\n\n", number, total);
+        assert_eq!(
+            split_with_prefix("short", 4096, split_message, prefix),
+            vec!["short"]
+        );
+    }
+
+    #[test]
+    fn split_with_prefix_handles_part_counts_past_999() {
+        let prefix = |number: usize, total: usize| format!("[{}/{}]\n\n", number, total);
+        let text = "a".repeat(10_000);
+        let chunks = split_with_prefix(&text, 20, split_message, prefix);
+        assert!(chunks.len() > 999);
+        assert!(lengths(&chunks).iter().all(|&len| len <= 20));
+        let total = chunks.len();
+        let body: String = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| &chunk[prefix(index + 1, total).len()..])
+            .collect();
+        assert_eq!(body, text);
+    }
+
+    #[test]
+    fn escape_html_keeps_angle_brackets_and_ampersands_literal() {
+        assert_eq!(
+            escape_for_parse_mode("free(<ptr>) if a && b > 0", Some("HTML")),
+            "free(&lt;ptr&gt;) if a &amp;&amp; b &gt; 0"
+        );
+        assert_eq!(escape_for_parse_mode("a && b", None), "a && b");
+    }
+
+    #[test]
+    fn escaped_plain_text_reads_back_unchanged() {
+        let text = "<ptr> & \"quoted\" &amp; 'x'";
+        assert_eq!(html_to_plain_text(&escape_html(text)), text);
+    }
+
+    #[test]
+    fn linkify_wraps_bare_urls_and_escapes_the_rest() {
+        assert_eq!(
+            linkify_plain_text("See https://example.com/a?b=1&c=2. <done>"),
+            "See <a href=\"https://example.com/a?b=1&amp;c=2\">https://example.com/a?b=1&amp;c=2</a>. &lt;done&gt;"
+        );
+        assert_eq!(
+            linkify_plain_text("(wiki http://w.org/A_(b)) and http://"),
+            "(wiki <a href=\"http://w.org/A_(b)\">http://w.org/A_(b)</a>) and http://"
+        );
+    }
+
+    #[test]
+    fn unflow_text_joins_soft_line_breaks() {
+        let flowed = "First line \r\nof a paragraph.\r\nHard break.\r\n \
+                      >From stuffed\r\n> Quoted \r\n> text\r\n>> Deeper\r\n-- \r\nSig";
+        assert_eq!(
+            unflow_text(flowed, false),
+            "First line of a paragraph.\nHard break.\n>From stuffed\n\
+             > Quoted text\n>> Deeper\n-- \nSig"
+        );
+        assert_eq!(unflow_text("Ab \r\ncd", true), "Abcd");
+    }
+
+    #[test]
+    fn parse_chat_id_accepts_numbers_and_usernames() {
+        assert_eq!(parse_chat_id(" -1001234567890 ").unwrap(), "-1001234567890");
+        assert_eq!(parse_chat_id("123456789").unwrap(), "123456789");
+        assert_eq!(parse_chat_id("@ops_alerts").unwrap(), "@ops_alerts");
+        for invalid in [
+            "",
+            "@my channel",
+            "'-100123'",
+            "@abc",
+            "@1alerts",
+            "ops",
+            "12a",
+            "0",
+        ] {
+            assert!(parse_chat_id(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn filters_check_sender_and_subject() {
+        let mut config = test_config();
+        config.from_allow = vec!["*@monitoring.example.com".to_string()];
+        config.from_block = vec!["noreply@*".to_string()];
+        config.subject_regex = Some(Regex::new("(?i)^(alert|critical):").unwrap());
+        let passes = |sender, subject| config.filter_reason(sender, subject).is_none();
+        assert!(passes(
+            Some("zabbix@monitoring.example.com"),
+            "ALERT: disk full"
+        ));
+        assert!(!passes(
+            Some("noreply@monitoring.example.com"),
+            "Alert: disk full"
+        ));
+        assert!(!passes(Some("cron@db1.example.com"), "Alert: disk full"));
+        assert!(!passes(None, "Alert: disk full"));
+        assert!(!passes(
+            Some("zabbix@monitoring.example.com"),
+            "Weekly report"
+        ));
+    }
+
+    #[test]
+    fn forward_errors_from_client_mistakes_are_permanent() {
+        let api_error = |status| ForwardError::TelegramApi {
+            status,
+            body: String::new(),
+        };
+        assert!(api_error(reqwest::StatusCode::BAD_REQUEST).is_permanent());
+        assert!(api_error(reqwest::StatusCode::FORBIDDEN).is_permanent());
+        assert!(!api_error(reqwest::StatusCode::TOO_MANY_REQUESTS).is_permanent());
+        assert!(!api_error(reqwest::StatusCode::BAD_GATEWAY).is_permanent());
+        assert!(ForwardError::EmptyMessage.is_permanent());
+        assert!(!ForwardError::Other(anyhow::anyhow!("limiter closed")).is_permanent());
+    }
+
+    fn test_config() -> SessionConfig {
+        SessionConfig {
+            telegram_token: RwLock::new("123:test".to_string()),
+            telegram_chat_ids: vec!["42".to_string()],
+            thread_id: None,
+            api_base_url: DEFAULT_TELEGRAM_API_URL.to_string(),
+            max_document_size: MAX_DOCUMENT_SIZE,
+            routes: Vec::new(),
+            sender_topics: Vec::new(),
+            from_allow: Vec::new(),
+            from_block: Vec::new(),
+            subject_regex: None,
+            buttons: Vec::new(),
+            auto_create_topics: false,
+            created_topics: Mutex::new(HashMap::new()),
+            topics_file: None,
+            include_headers: Vec::new(),
+            allow_parse_mode_header: false,
+            show_auth_results: false,
+            no_whitespace_cleanup: false,
+            chunk_header: false,
+            prefer_header_addresses: false,
+            prefer: PreferredPart::Html,
+            strip_quotes: false,
+            no_link_preview: false,
+            silent: false,
+            dry_run: true,
+            timezone: chrono_tz::UTC,
+            show_unsubscribe: false,
+            parse_mode: ParseMode::None,
+            linkify: false,
+            transforms: Vec::new(),
+            max_message_size: 1024 * 1024,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            split_lookback: DEFAULT_SPLIT_LOOKBACK,
+            long_as_document: false,
+            max_recipients: 100,
+            io_timeout: 5,
+            greeting_delay: 0,
+            telegram_permits: Semaphore::new(4),
+            max_send_attempts: 1,
+            confirm_delivery: false,
+            flood_limit: 0,
+            flood_window: Duration::from_secs(60),
+            flood_windows: Mutex::new(HashMap::new()),
+            rate_limit: 0.0,
+            chat_rate_limit: 0.0,
+            chunk_delay: Duration::ZERO,
+            rate_buckets: Mutex::new((TokenBucket::full(0.0, Instant::now()), HashMap::new())),
+            dedupe_ttl: Duration::ZERO,
+            seen_messages: Mutex::new(SeenMessages::default()),
+            spool: None,
+            metrics: Metrics::default(),
+            hostname: "mx.test".to_string(),
+            tls_acceptor: None,
+            auth_credentials: None,
+            empty_placeholder: None,
+            attach_eml: None,
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+
+    // Runs an SMTP session with `config` on a local socket and returns the client side
+    async fn connect(config: SessionConfig) -> BufReader<TcpStream> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let telegram = TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(config));
+        tokio::spawn(async move {
+            SmtpSession::new(SmtpStream::Plain(server), 1, telegram)
+                .handle()
+                .await
+        });
+        BufReader::new(client)
+    }
+
+    // Sends a line (or nothing) and returns the reply, with continuation lines
+    async fn exchange(client: &mut BufReader<TcpStream>, line: &str) -> String {
+        client.get_mut().write_all(line.as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        loop {
+            let mut reply_line = String::new();
+            client.read_line(&mut reply_line).await.unwrap();
+            reply.push_str(&reply_line);
+            if reply_line.as_bytes().get(3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
+
+    // A request received by FakeTelegram. Uploaded files are recorded by their filename.
+    #[derive(Clone, Debug)]
+    struct FakeRequest {
+        token: String,
+        method: String,
+        fields: HashMap<String, String>,
+    }
+
+    // A stand-in for the Bot API on a local socket. Answers each request with the status
+    // and JSON body returned by `reply`.
+    struct FakeTelegram {
+        base_url: String,
+        requests: Arc<Mutex<Vec<FakeRequest>>>,
+    }
+
+    impl FakeTelegram {
+        async fn start<F>(reply: F) -> Self
+        where
+            F: Fn(&FakeRequest) -> (u16, String) + Send + Sync + 'static,
+        {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let reply = Arc::new(reply);
+            let recorded = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let reply = reply.clone();
+                    let recorded = recorded.clone();
+                    tokio::spawn(async move {
+                        let mut stream = BufReader::new(stream);
+                        while let Some(request) = read_fake_request(&mut stream).await {
+                            let (status, body) = reply(&request);
+                            recorded.lock().unwrap().push(request);
+                            let response = format!(
+                                "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                status,
+                                body.len(),
+                                body
+                            );
+                            if stream
+                                .get_mut()
+                                .write_all(response.as_bytes())
+                                .await
+                                .is_err()
+                            {
+                                return;
+                            }
+                        }
+                    });
+                }
+            });
+            FakeTelegram { base_url, requests }
+        }
+
+        // Accepts every request like the Bot API does for a sent message
+        async fn ok() -> Self {
+            Self::start(|_| (200, FAKE_OK.to_string())).await
+        }
+
+        fn requests(&self) -> Vec<FakeRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        // The given field of every request to `method`, in order
+        fn fields(&self, method: &str, field: &str) -> Vec<String> {
+            self.requests()
+                .into_iter()
+                .filter(|request| request.method == method)
+                .map(|request| request.fields.get(field).cloned().unwrap_or_default())
+                .collect()
+        }
+
+        // `config` sending to this server
+        fn configure(&self, mut config: SessionConfig) -> SessionConfig {
+            config.api_base_url = self.base_url.clone();
+            config.dry_run = false;
+            config
+        }
+
+        fn client(&self, config: SessionConfig) -> TelegramClient {
+            TelegramClient::new(
+                Arc::new(reqwest::Client::new()),
+                Arc::new(self.configure(config)),
+            )
+        }
+    }
+
+    const FAKE_OK: &str =
+        r#"{"ok":true,"result":{"message_id":1,"message_thread_id":77,"id":42,"username":"bot"}}"#;
+
+    // Reads one HTTP request, None when the client closed the connection
+    async fn read_fake_request(stream: &mut BufReader<TcpStream>) -> Option<FakeRequest> {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.ok()? == 0 {
+            return None;
+        }
+        let path = request_line.split_whitespace().nth(1)?.to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.ok()?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let mut body = Vec::new();
+        if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+            loop {
+                let mut size = String::new();
+                stream.read_line(&mut size).await.ok()?;
+                let size = usize::from_str_radix(size.trim(), 16).ok()?;
+                let mut chunk = vec![0; size + 2];
+                stream.read_exact(&mut chunk).await.ok()?;
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else {
+            let length = headers
+                .get("content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            body.resize(length, 0);
+            stream.read_exact(&mut body).await.ok()?;
+        }
+
+        // The path is /bot<token>/<method>
+        let path = path.split('?').next().unwrap_or_default();
+        let (token, method) = path.strip_prefix("/bot")?.split_once('/')?;
+        let content_type = headers.get("content-type").cloned().unwrap_or_default();
+        let fields = match content_type.split_once("boundary=") {
+            Some((_, boundary)) => parse_fake_multipart(&body, boundary),
+            None => String::from_utf8_lossy(&body)
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (fake_form_decode(name), fake_form_decode(value)))
+                .collect(),
+        };
+        Some(FakeRequest {
+            token: token.to_string(),
+            method: method.to_string(),
+            fields,
+        })
+    }
+
+    fn fake_form_decode(value: &str) -> String {
+        percent_encoding::percent_decode_str(&value.replace('+', " "))
+            .decode_utf8_lossy()
+            .to_string()
+    }
+
+    fn parse_fake_multipart(body: &[u8], boundary: &str) -> HashMap<String, String> {
+        let body = String::from_utf8_lossy(body);
+        let mut fields = HashMap::new();
+        for part in body.split(&format!("--{}", boundary)) {
+            let Some((head, value)) = part.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let attribute = |name: &str| {
+                head.split(&format!("{}=\"", name))
+                    .nth(1)
+                    .and_then(|rest| rest.split('"').next())
+                    .map(str::to_string)
+            };
+            let Some(name) = attribute("name") else {
+                continue;
+            };
+            let value = value.strip_suffix("\r\n").unwrap_or(value);
+            let value = match attribute("filename") {
+                Some(filename) => format!("<file {}>", filename),
+                None => value.to_string(),
+            };
+            fields.insert(name, value);
+        }
+        fields
+    }
+
+    #[tokio::test]
+    async fn session_accepts_bare_lf_line_endings() {
+        let mut client = connect(test_config()).await;
+        assert!(exchange(&mut client, "").await.starts_with("220 mx.test"));
+        assert!(exchange(&mut client, "EHLO client\n")
+            .await
+            .starts_with("250-mx.test"));
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\n")
+            .await
+            .starts_with("250"));
+        assert!(exchange(&mut client, "RCPT TO:<b@example.com>\n")
+            .await
+            .starts_with("250"));
+        assert!(exchange(&mut client, "DATA\n").await.starts_with("354"));
+        let reply = exchange(&mut client, "Subject: Hi\n\nBody\n..dot\n.\n").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        assert!(exchange(&mut client, "QUIT\n").await.starts_with("221"));
+    }
+
+    #[tokio::test]
+    async fn session_rejects_oversized_mail_before_data() {
+        let mut config = test_config();
+        config.max_message_size = 1000;
+        let mut client = connect(config).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        assert!(
+            exchange(&mut client, "MAIL FROM:<a@example.com> SIZE=1001\r\n")
+                .await
+                .starts_with("552")
+        );
+        // The transaction never started, so there is nothing to send
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        assert!(exchange(&mut client, "DATA\r\n").await.starts_with("503"));
+        assert!(
+            exchange(&mut client, "MAIL FROM:<a@example.com> SIZE=1000\r\n")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn session_drops_partial_data_on_disconnect() {
+        let config = Arc::new(test_config());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let telegram = TelegramClient::new(Arc::new(reqwest::Client::new()), config.clone());
+        let session = tokio::spawn(async move {
+            SmtpSession::new(SmtpStream::Plain(server), 1, telegram)
+                .handle()
+                .await
+        });
+        let mut client = BufReader::new(client);
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        assert!(exchange(&mut client, "DATA\r\n").await.starts_with("354"));
+        client
+            .get_mut()
+            .write_all(b"Subject: Hi\r\n\r\nPartial body")
+            .await
+            .unwrap();
+        drop(client);
+        // A clean end of the session, and nothing reached the forwarding path
+        assert!(session.await.unwrap().is_ok());
+        assert_eq!(config.metrics.emails_received.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn extract_falls_back_to_text_when_html_part_is_empty() {
+        let telegram =
+            TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(test_config()));
+        let email = telegram.extract_text_from_email(
+            b"Subject: Report\r\n\
+              Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+              \r\n\
+              --b\r\n\
+              Content-Type: text/plain\r\n\
+              \r\n\
+              Backup finished\r\n\
+              --b\r\n\
+              Content-Type: text/html\r\n\
+              \r\n\
+              \r\n\
+              --b--\r\n",
+        );
+        assert_eq!(email.body, "Backup finished");
+        assert_eq!(email.content_type.as_deref(), Some("text/plain"));
+    }
+
+    #[tokio::test]
+    async fn forward_shows_audit_routing_headers() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.include_headers = resolve_include_headers(&["audit".to_string()]);
+        let telegram = telegram_api.client(config);
+        let email = b"Delivered-To: ops@example.com\r\n\
+                      X-Original-To: alerts@example.com\r\n\
+                      Subject: Disk full\r\n\
+                      \r\n\
+                      /var is at 98%\r\n";
+        let forwarded = telegram
+            .forward_message(
+                email,
+                Some("cron@example.com"),
+                &["ops@example.com".to_string()],
+                None,
+            )
+            .await;
+        assert!(forwarded.is_ok());
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 1);
+        assert!(
+            texts[0].contains("Delivered-To: ops@example.com\n"),
+            "{}",
+            texts[0]
+        );
+        assert!(
+            texts[0].contains("X-Original-To: alerts@example.com\n"),
+            "{}",
+            texts[0]
+        );
+    }
+
+    fn extract(config: SessionConfig, email: &[u8]) -> ExtractedEmail {
+        TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(config))
+            .extract_text_from_email(email)
+    }
+
+    #[test]
+    fn extract_uses_html_part_of_mixed_and_related_messages() {
+        for multipart in ["mixed", "related"] {
+            let email = format!(
+                "Subject: Newsletter\r\n\
+                 Content-Type: multipart/{}; boundary=\"b\"\r\n\
+                 \r\n\
+                 --b\r\n\
+                 Content-Type: text/html\r\n\
+                 \r\n\
+                 <p>Hello <b>world</b></p>\r\n\
+                 --b\r\n\
+                 Content-Type: image/png\r\n\
+                 Content-Disposition: inline; filename=\"logo.png\"\r\n\
+                 \r\n\
+                 PNG\r\n\
+                 --b--\r\n",
+                multipart
+            );
+            let email = extract(test_config(), email.as_bytes());
+            assert_eq!(
+                email.content_type.as_deref(),
+                Some("text/html"),
+                "{}",
+                multipart
+            );
+            assert!(email.body.contains("<b>world</b>"), "{}", email.body);
+        }
+    }
+
+    fn convert_html(html: &str) -> String {
+        TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(test_config()))
+            .convert_html_to_telegram(html)
+    }
+
+    #[test]
+    fn convert_html_renders_nested_lists_as_text() {
+        let html = "<ul><li>One<ul><li>Nested</li></ul></li>\n\
+                    <li>Two<ol><li>First</li><li>Second</li></ol></li></ul>";
+        assert_eq!(
+            convert_html(html),
+            "• One\n  • Nested\n• Two\n  1. First\n  2. Second"
+        );
+    }
+
+    #[test]
+    fn convert_html_renders_headings_as_bold_lines() {
+        assert_eq!(
+            convert_html("<h1>Report</h1><h2>Summary</h2><p>All good</p><h6>Footer</h6>"),
+            "<b>Report</b>\n<b>Summary</b>\n\nAll good\n\n<b>Footer</b>"
+        );
+    }
+
+    #[test]
+    fn convert_html_keeps_paragraphs_and_line_breaks() {
+        // Runs of <br> between paragraphs don't add more than one blank line
+        assert_eq!(
+            convert_html(
+                "<p>First paragraph</p>\n<p>Second<br>line two</p><br><br><br><p>Third</p>"
+            ),
+            "First paragraph\n\nSecond\nline two\n\nThird"
+        );
+    }
+
+    // Forwards `email` from the envelope sender a@example.com to b@example.com
+    async fn forward(telegram: &TelegramClient, email: &[u8]) -> Result<(), FailedForward> {
+        telegram
+            .forward_message(
+                email,
+                Some("a@example.com"),
+                &["b@example.com".to_string()],
+                None,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn forward_escapes_subject_in_html_mode() {
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        let email = b"Subject: 5 < 10 & rising\r\n\
+                      Content-Type: text/html\r\n\
+                      \r\n\
+                      <p>Load is <b>high</b></p>\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+        let text = &telegram_api.fields("sendMessage", "text")[0];
+        assert!(
+            text.contains("Subject: 5 &lt; 10 &amp; rising\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("Load is <b>high</b>"), "{}", text);
+        assert_eq!(telegram_api.fields("sendMessage", "parse_mode"), ["HTML"]);
+    }
+
+    fn auth_config() -> SessionConfig {
+        let mut config = test_config();
+        config.auth_credentials = Some(("sender".to_string(), "secret".to_string()));
+        config
+    }
+
+    #[tokio::test]
+    async fn session_accepts_auth_plain_with_configured_credentials() {
+        let mut client = connect(auth_config()).await;
+        exchange(&mut client, "").await;
+        let ehlo = exchange(&mut client, "EHLO client\r\n").await;
+        assert!(ehlo.contains("AUTH PLAIN LOGIN\r\n"), "{}", ehlo);
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+            .await
+            .starts_with("530"));
+        let plain = general_purpose::STANDARD.encode("\0sender\0secret");
+        let reply = exchange(&mut client, &format!("AUTH PLAIN {}\r\n", plain)).await;
+        assert!(reply.starts_with("235"), "{}", reply);
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+            .await
+            .starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn session_accepts_auth_login_with_configured_credentials() {
+        let mut client = connect(auth_config()).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        assert!(exchange(&mut client, "AUTH LOGIN\r\n")
+            .await
+            .starts_with("334 VXNlcm5hbWU6"));
+        let user = format!("{}\r\n", general_purpose::STANDARD.encode("sender"));
+        assert!(exchange(&mut client, &user)
+            .await
+            .starts_with("334 UGFzc3dvcmQ6"));
+        let pass = format!("{}\r\n", general_purpose::STANDARD.encode("secret"));
+        assert!(exchange(&mut client, &pass).await.starts_with("235"));
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+            .await
+            .starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn session_rejects_wrong_auth_credentials() {
+        let mut client = connect(auth_config()).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        let plain = general_purpose::STANDARD.encode("\0sender\0wrong");
+        let reply = exchange(&mut client, &format!("AUTH PLAIN {}\r\n", plain)).await;
+        assert!(reply.starts_with("535"), "{}", reply);
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+            .await
+            .starts_with("530"));
+    }
+
+    #[tokio::test]
+    async fn session_refuses_auth_without_configured_credentials() {
+        let mut client = connect(test_config()).await;
+        exchange(&mut client, "").await;
+        let ehlo = exchange(&mut client, "EHLO client\r\n").await;
+        assert!(!ehlo.contains("AUTH"), "{}", ehlo);
+        let plain = general_purpose::STANDARD.encode("\0anyone\0anything");
+        let reply = exchange(&mut client, &format!("AUTH PLAIN {}\r\n", plain)).await;
+        assert!(reply.starts_with("502 5.5.1"), "{}", reply);
+        assert!(exchange(&mut client, "AUTH LOGIN\r\n")
+            .await
+            .starts_with("502 5.5.1"));
+        // The session goes on without authentication
+        assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+            .await
+            .starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn forward_escapes_display_names_in_html_mode() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.prefer_header_addresses = true;
+        let telegram = telegram_api.client(config);
+        let email = b"From: \"<admin>\" <admin@example.com>\r\n\
+                      To: ops@example.com\r\n\
+                      Subject: Report\r\n\
+                      Content-Type: text/html\r\n\
+                      \r\n\
+                      <p>All good</p>\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+        let text = &telegram_api.fields("sendMessage", "text")[0];
+        assert!(
+            text.contains("From: &lt;admin&gt; &lt;admin@example.com&gt;\n"),
+            "{}",
+            text
+        );
+        assert_eq!(telegram_api.fields("sendMessage", "parse_mode"), ["HTML"]);
+    }
+
+    #[test]
+    fn telegram_method_url_rejects_unknown_methods() {
+        assert_eq!(
+            telegram_method_url("https://api.example.com", "123:abc", "sendMessage").unwrap(),
+            "https://api.example.com/bot123:abc/sendMessage"
+        );
+        let error =
+            telegram_method_url("https://api.example.com", "123:abc", "deleteMessage").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Telegram API method not allowed: deleteMessage"
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_empty_email_as_placeholder() {
+        let email = b"\r\n";
+
+        // Dropped by default
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        assert!(forward(&telegram, email).await.is_ok());
+        assert!(telegram_api.requests().is_empty());
+
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.empty_placeholder = Some("(empty email from {from})".to_string());
+        let telegram = telegram_api.client(config);
+        assert!(forward(&telegram, email).await.is_ok());
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 1);
+        assert!(
+            texts[0].ends_with("(empty email from a@example.com)"),
+            "{}",
+            texts[0]
+        );
+    }
+
+    #[test]
+    fn extract_summarizes_authentication_results() {
+        let mut config = test_config();
+        config.show_auth_results = true;
+        let email = extract(
+            config,
+            b"Authentication-Results: mx.example.com;\r\n\
+              \tdkim=fail (bad signature) header.d=example.com;\r\n\
+              \tspf=pass smtp.mailfrom=example.com; dmarc=fail\r\n\
+              Received-SPF: Softfail (domain does not designate)\r\n\
+              Subject: Invoice\r\n\
+              \r\n\
+              Please pay\r\n",
+        );
+        assert!(
+            email
+                .header_lines
+                .contains(&"Auth: spf=pass dkim=fail dmarc=fail".to_string()),
+            "{:?}",
+            email.header_lines
+        );
+
+        // Received-SPF alone still gives the SPF result
+        let mut config = test_config();
+        config.show_auth_results = true;
+        let email = extract(
+            config,
+            b"Received-SPF: Pass (sender SPF authorized)\r\nSubject: Hi\r\n\r\nBody\r\n",
+        );
+        assert!(email.header_lines.contains(&"Auth: spf=pass".to_string()));
+    }
+
+    #[tokio::test]
+    async fn session_finishes_data_after_a_line_ending_in_a_dot() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut client = connect(telegram_api.configure(test_config())).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        assert!(exchange(&mut client, "DATA\r\n").await.starts_with("354"));
+        // "done." doesn't end the message, the terminator on its own line does
+        client
+            .get_mut()
+            .write_all(b"Subject: Backup\r\n\r\nfirst line\r\ndone.\r\n")
+            .await
+            .unwrap();
+        let reply = exchange(&mut client, ".\r\n").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 1);
+        assert!(texts[0].ends_with("first line\ndone."), "{}", texts[0]);
+    }
+
+    #[tokio::test]
+    async fn telegram_requests_stay_within_the_concurrency_limit() {
+        // A Bot API that takes a while to answer and records the most requests in flight
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let most_in_flight = Arc::new(AtomicU64::new(0));
+        let (current, most) = (in_flight.clone(), most_in_flight.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (current, most) = (current.clone(), most.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    while read_fake_request(&mut stream).await.is_some() {
+                        let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        current.fetch_sub(1, Ordering::SeqCst);
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            FAKE_OK.len(),
+                            FAKE_OK
+                        );
+                        stream
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut config = test_config();
+        config.api_base_url = base_url;
+        config.dry_run = false;
+        config.telegram_permits = Semaphore::new(2);
+        let telegram = TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(config));
+        let sends: Vec<_> = (0..8)
+            .map(|i| {
+                let telegram = telegram.clone();
+                tokio::spawn(async move {
+                    let email = format!("Subject: Alert {}\r\n\r\nBody\r\n", i);
+                    forward(&telegram, email.as_bytes()).await.is_ok()
+                })
+            })
+            .collect();
+        for send in sends {
+            assert!(send.await.unwrap());
+        }
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn transforms_apply_in_the_given_order() {
+        // The fullwidth at sign only reads as an address after normalization
+        let body = "Contact ops＠example.com".to_string();
+        assert_eq!(
+            apply_transforms(
+                body.clone(),
+                &[BodyTransform::NormalizeUnicode, BodyTransform::Redact]
+            ),
+            "Contact [redacted]"
+        );
+        assert_eq!(
+            apply_transforms(
+                body,
+                &[BodyTransform::Redact, BodyTransform::NormalizeUnicode]
+            ),
+            "Contact ops@example.com"
+        );
+
+        let body = "See  https://example.com/a \n\n\n> old reply\nEnd".to_string();
+        assert_eq!(
+            apply_transforms(
+                body,
+                &[
+                    BodyTransform::TrimQuotes,
+                    BodyTransform::StripUrls,
+                    BodyTransform::CollapseWhitespace
+                ]
+            ),
+            "See\n\nEnd"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_use_the_reloaded_token() {
+        let path =
+            std::env::temp_dir().join(format!("smtp-to-telegram-token-{}", std::process::id()));
+        std::fs::write(&path, "123:old\n").unwrap();
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.telegram_token = RwLock::new(read_token_file(&path).unwrap());
+        let telegram = telegram_api.client(config);
+        assert!(forward(&telegram, b"Subject: First\r\n\r\nBody\r\n")
+            .await
+            .is_ok());
+
+        std::fs::write(&path, "456:new\n").unwrap();
+        telegram.config.reload_token(&path).unwrap();
+        assert!(forward(&telegram, b"Subject: Second\r\n\r\nBody\r\n")
+            .await
+            .is_ok());
+
+        // A token file that can't be read keeps the current token
+        std::fs::write(&path, "\n").unwrap();
+        assert!(telegram.config.reload_token(&path).is_err());
+        assert!(forward(&telegram, b"Subject: Third\r\n\r\nBody\r\n")
+            .await
+            .is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        let tokens: Vec<String> = telegram_api
+            .requests()
+            .into_iter()
+            .map(|r| r.token)
+            .collect();
+        assert_eq!(tokens, ["123:old", "456:new", "456:new"]);
+    }
+
+    #[test]
+    fn extract_shows_a_body_repeating_the_subject_once() {
+        let email = extract(
+            test_config(),
+            b"Subject: CPU load high on web1\r\n\r\nCPU load high on web1\r\n",
+        );
+        assert_eq!(email.body, "CPU load high on web1");
+        assert!(email.header_lines.is_empty(), "{:?}", email.header_lines);
+
+        // A body that is part of the subject is covered by the subject too
+        let email = extract(
+            test_config(),
+            b"Subject: [PROBLEM] CPU load high on web1\r\n\r\nCPU load high\r\n",
+        );
+        assert_eq!(email.body, "[PROBLEM] CPU load high on web1");
+        assert!(email.header_lines.is_empty(), "{:?}", email.header_lines);
+
+        // Different text keeps both
+        let email = extract(
+            test_config(),
+            b"Subject: Disk full\r\n\r\n/var is at 98%\r\n",
+        );
+        assert_eq!(email.body, "/var is at 98%");
+        assert_eq!(email.header_lines, ["Subject: Disk full"]);
+
+        // Without a subject the body is shown as is
+        let email = extract(
+            test_config(),
+            b"From: a@example.com\r\n\r\nCPU load high\r\n",
+        );
+        assert_eq!(email.body, "CPU load high");
+        assert!(email.header_lines.is_empty(), "{:?}", email.header_lines);
+    }
+
+    #[tokio::test]
+    async fn forward_puts_each_sender_in_its_topic() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.sender_topics = vec![
+            parse_sender_topic("backup@example.com=10").unwrap(),
+            parse_sender_topic("*@monitoring.example.com=20").unwrap(),
+        ];
+        config.auto_create_topics = true;
+        let telegram = telegram_api.client(config);
+        let recipients = ["ops@example.com".to_string()];
+        for sender in [
+            "backup@example.com",
+            "disk@monitoring.example.com",
+            "new@example.com",
+            "new@example.com",
+        ] {
+            let forwarded = telegram
+                .forward_message(
+                    b"Subject: Report\r\n\r\nBody\r\n",
+                    Some(sender),
+                    &recipients,
+                    None,
+                )
+                .await;
+            assert!(forwarded.is_ok());
+        }
+        assert_eq!(
+            telegram_api.fields("sendMessage", "message_thread_id"),
+            ["10", "20", "77", "77"]
+        );
+        // The topic created for an unmapped sender is reused
+        assert_eq!(
+            telegram_api.fields("createForumTopic", "name"),
+            ["new@example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_keeps_preformatted_whitespace_and_normalizes_prose() {
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        let email = b"Subject: Build\r\n\
+                      Content-Type: text/html\r\n\
+                      \r\n\
+                      <p>  Build   failed\r\n\r\n\r\n   in   step 3</p>\r\n\
+                      <pre>Traceback:\r\n    File \"x.py\", line 1\r\n\r\n      raise Error</pre>\r\n\
+                      <p>   Fix   it  </p>\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+        let text = &telegram_api.fields("sendMessage", "text")[0];
+        assert!(
+            text.ends_with(
+                "Build failed\nin step 3\n\n\
+                 <pre>Traceback:\n    File \"x.py\", line 1\n\n      raise Error</pre>\n\n\
+                 Fix it"
+            ),
+            "{:?}",
+            text
+        );
+    }
+
+    #[tokio::test]
+    async fn session_rejects_clients_talking_before_the_greeting() {
+        let mut config = test_config();
+        config.greeting_delay = 200;
+        let mut client = connect(config).await;
+        let reply = exchange(&mut client, "EHLO spambot\r\n").await;
+        assert!(reply.starts_with("554"), "{}", reply);
+        // The connection is closed after the rejection
+        let mut rest = String::new();
+        assert_eq!(client.read_line(&mut rest).await.unwrap(), 0);
+
+        let mut config = test_config();
+        config.greeting_delay = 200;
+        let mut client = connect(config).await;
+        let started = Instant::now();
+        assert!(exchange(&mut client, "").await.starts_with("220 mx.test"));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(exchange(&mut client, "EHLO client\r\n")
+            .await
+            .starts_with("250"));
+    }
+
+    #[test]
+    fn extract_shows_unsubscribe_links() {
+        let mut config = test_config();
+        config.show_unsubscribe = true;
+        let email = extract(
+            config,
+            b"List-Unsubscribe: <mailto:leave@example.com?subject=unsubscribe>,\r\n\
+              \x20<https://example.com/unsubscribe/abc>\r\n\
+              List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n\
+              Subject: Weekly news\r\n\
+              \r\n\
+              News\r\n",
+        );
+        assert!(
+            email.header_lines.contains(
+                &"Unsubscribe: https://example.com/unsubscribe/abc (one-click), \
+                  mailto:leave@example.com?subject=unsubscribe"
+                    .to_string()
+            ),
+            "{:?}",
+            email.header_lines
+        );
+
+        // Without List-Unsubscribe-Post only the links are shown
+        let mut config = test_config();
+        config.show_unsubscribe = true;
+        let email = extract(
+            config,
+            b"List-Unsubscribe: <mailto:leave@example.com>\r\nSubject: News\r\n\r\nNews\r\n",
+        );
+        assert!(email
+            .header_lines
+            .contains(&"Unsubscribe: mailto:leave@example.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn forward_attaches_inline_keyboard() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.buttons = vec![
+            parse_button("View in dashboard=https://dash.example.com/search?q={subject}").unwrap(),
+            parse_button("Sender=https://dash.example.com/senders/{from}").unwrap(),
+        ];
+        let telegram = telegram_api.client(config);
+        assert!(
+            forward(&telegram, b"Subject: Disk full & more\r\n\r\nBody\r\n")
+                .await
+                .is_ok()
+        );
+        let markup = &telegram_api.fields("sendMessage", "reply_markup")[0];
+        let markup: serde_json::Value = serde_json::from_str(markup).unwrap();
+        assert_eq!(
+            markup,
+            serde_json::json!({
+                "inline_keyboard": [
+                    [{
+                        "text": "View in dashboard",
+                        "url": "https://dash.example.com/search?q=Disk%20full%20%26%20more"
+                    }],
+                    [{
+                        "text": "Sender",
+                        "url": "https://dash.example.com/senders/a%40example.com"
+                    }]
+                ]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn session_treats_commands_inside_data_as_content() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut client = connect(telegram_api.configure(test_config())).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        assert!(exchange(&mut client, "DATA\r\n").await.starts_with("354"));
+        // Neither RSET nor QUIT leave DATA, and nothing is forwarded without the terminator
+        client
+            .get_mut()
+            .write_all(b"Subject: Steps\r\n\r\nRSET\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(telegram_api.requests().is_empty());
+
+        let mut client = connect(telegram_api.configure(test_config())).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        exchange(&mut client, "DATA\r\n").await;
+        let reply = exchange(&mut client, "Subject: Steps\r\n\r\nRSET\r\nQUIT\r\n.\r\n").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 1);
+        assert!(texts[0].ends_with("RSET\nQUIT"), "{}", texts[0]);
+    }
+
+    #[tokio::test]
+    async fn flood_limit_suppresses_a_burst_and_reports_it() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.flood_limit = 3;
+        config.flood_window = Duration::from_secs(1);
+        let telegram = telegram_api.client(config);
+        let alert = |i: usize| format!("Subject: Alert {}\r\n\r\nBody\r\n", i);
+
+        // A burst of 5 in one window: 3 go out, 2 are suppressed
+        for i in 1..=5 {
+            assert!(forward(&telegram, alert(i).as_bytes()).await.is_ok());
+        }
+        assert_eq!(telegram_api.fields("sendMessage", "text").len(), 3);
+
+        // The next window starts with the notice, then forwards up to the limit again
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        for i in 6..=8 {
+            assert!(forward(&telegram, alert(i).as_bytes()).await.is_ok());
+        }
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 7);
+        assert_eq!(
+            texts[3],
+            "⚠️ Suppressed 2 message(s) over the flood limit of 3 per 1 seconds"
+        );
+        // Alerts 4 and 5 were dropped
+        for (text, alert) in texts.iter().zip([1, 2, 3, 0, 6, 7, 8]) {
+            if alert > 0 {
+                assert!(
+                    text.contains(&format!("Subject: Alert {}\n", alert)),
+                    "{}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn extract_prefers_the_text_part_when_configured() {
+        let alternative = b"Subject: Report\r\n\
+                            Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                            \r\n\
+                            --b\r\n\
+                            Content-Type: text/plain\r\n\
+                            \r\n\
+                            Plain report\r\n\
+                            --b\r\n\
+                            Content-Type: text/html\r\n\
+                            \r\n\
+                            <p><b>HTML</b> report</p>\r\n\
+                            --b--\r\n";
+        let email = extract(test_config(), alternative);
+        assert_eq!(email.content_type.as_deref(), Some("text/html"));
+
+        let mut config = test_config();
+        config.prefer = PreferredPart::Text;
+        let email = extract(config, alternative);
+        assert_eq!(email.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(email.body, "Plain report");
+
+        // Without a text part the HTML is still used
+        let mut config = test_config();
+        config.prefer = PreferredPart::Text;
+        let email = extract(
+            config,
+            b"Subject: Report\r\n\
+              Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+              \r\n\
+              --b\r\n\
+              Content-Type: text/html\r\n\
+              \r\n\
+              <p><b>HTML</b> report</p>\r\n\
+              --b--\r\n",
+        );
+        assert_eq!(email.content_type.as_deref(), Some("text/html"));
+    }
+
+    #[tokio::test]
+    async fn forward_labels_every_part_with_chunk_header() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.chunk_header = true;
+        config.max_message_length = 300;
+        let telegram = telegram_api.client(config);
+        let email = format!(
+            "Message-ID: <42@example.com>\r\nSubject: Nightly log\r\n\r\n{}",
+            log_lines("web1", 8)
+        );
+        assert!(forward(&telegram, email.as_bytes()).await.is_ok());
+
+        // The id comes from the Message-ID, so that every part shows the same one
+        let label = chunk_label("Nightly log", b"42@example.com");
+        assert!(
+            label.starts_with('#') && label.ends_with(" Nightly log"),
+            "{}",
+            label
+        );
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert!(texts.len() > 1);
+        for (index, text) in texts.iter().enumerate() {
+            let header = format!("[part {} of {}] {}\n\n", index + 1, texts.len(), label);
+            assert!(text.starts_with(&header), "{}", text);
+            assert!(text.chars().count() <= 300);
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_uses_the_parse_mode_header_when_allowed() {
+        let email = b"Subject: Deploy\r\n\
+                      X-Telegram-Parse-Mode: Markdown\r\n\
+                      \r\n\
+                      *done* in _3m_\r\n";
+
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.allow_parse_mode_header = true;
+        let telegram = telegram_api.client(config);
+        assert!(forward(&telegram, email).await.is_ok());
+        assert_eq!(
+            telegram_api.fields("sendMessage", "parse_mode"),
+            ["Markdown"]
+        );
+        let text = &telegram_api.fields("sendMessage", "text")[0];
+        assert!(text.ends_with("*done* in _3m_"), "{}", text);
+
+        // Ignored unless --allow-parse-mode-header is set
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        assert!(forward(&telegram, email).await.is_ok());
+        assert_eq!(telegram_api.fields("sendMessage", "parse_mode"), [""]);
+
+        // Unsupported values are ignored too
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.allow_parse_mode_header = true;
+        let telegram = telegram_api.client(config);
+        let email = b"Subject: Deploy\r\nX-Telegram-Parse-Mode: bbcode\r\n\r\n[b]done[/b]\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+        assert_eq!(telegram_api.fields("sendMessage", "parse_mode"), [""]);
+    }
+
+    #[tokio::test]
+    async fn forward_removes_nul_bytes_from_the_body() {
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        let email = b"Subject: Dump\0ed\r\n\r\nfirst\0line\x07\r\n\tsecond line\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+        let text = &telegram_api.fields("sendMessage", "text")[0];
+        assert!(
+            !text.contains(|c: char| c.is_control() && c != '\n' && c != '\t'),
+            "{:?}",
+            text
+        );
+        assert!(text.ends_with("firstline\nsecond line"), "{:?}", text);
+        assert!(text.contains("Subject: Dumped\n"), "{:?}", text);
+    }
+
+    #[tokio::test]
+    async fn session_answers_garbage_and_keeps_the_connection() {
+        let mut client = connect(test_config()).await;
+        exchange(&mut client, "").await;
+        let reply = exchange(&mut client, "\x01\x02 garbage !!\r\n").await;
+        assert!(reply.starts_with("500 5.5.2"), "{}", reply);
+        assert!(exchange(&mut client, "MAIL\r\n").await.starts_with("5"));
+        assert!(exchange(&mut client, "QUIT\r\n").await.starts_with("221"));
+    }
+
+    #[tokio::test]
+    async fn spooled_retry_sends_only_to_failed_chats() {
+        let dir =
+            std::env::temp_dir().join(format!("smtp-to-telegram-spool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let telegram_api = FakeTelegram::start({
+            let down = down.clone();
+            move |request| match request.fields.get("chat_id").map(String::as_str) {
+                Some("-1002") if down.load(Ordering::Relaxed) => (
+                    502,
+                    r#"{"ok":false,"error_code":502,"description":"Bad Gateway"}"#.to_string(),
+                ),
+                _ => (200, FAKE_OK.to_string()),
+            }
+        })
+        .await;
+        let mut config = test_config();
+        config.telegram_chat_ids = vec!["-1001".to_string(), "-1002".to_string()];
+        config.spool = Some(Spool {
+            dir: dir.clone(),
+            max_age: Duration::from_secs(3600),
+            max_size: 1024 * 1024,
+        });
+        let telegram = telegram_api.client(config);
+        let email = b"Subject: Disk full\r\n\r\n/var is at 98%\r\n";
+
+        let Err(failed) = forward(&telegram, email).await else {
+            panic!("the send to -1002 should fail");
+        };
+        telegram
+            .spool_message(email, Some("a@example.com"), &failed)
+            .await
+            .unwrap();
+        assert_eq!(
+            telegram_api.fields("sendMessage", "chat_id"),
+            ["-1001", "-1002"]
+        );
+
+        down.store(false, Ordering::Relaxed);
+        telegram.retry_spool().await.unwrap();
+        assert_eq!(
+            telegram_api.fields("sendMessage", "chat_id"),
+            ["-1001", "-1002", "-1002"]
+        );
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_drops_empty_email_with_declared_content_type() {
+        for content_type in ["text/plain", "text/html"] {
+            let email = format!("Content-Type: {}\r\n\r\n\r\n", content_type);
+            let telegram_api = FakeTelegram::ok().await;
+            let telegram = telegram_api.client(test_config());
+            assert!(forward(&telegram, email.as_bytes()).await.is_ok());
+            assert!(
+                telegram_api.requests().is_empty(),
+                "{}: {:?}",
+                content_type,
+                telegram_api.fields("sendMessage", "text")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn session_refuses_huge_second_bdat_chunk() {
+        let mut config = test_config();
+        config.max_message_size = 1000;
+        config.io_timeout = 1;
+        let mut client = connect(config).await;
+        exchange(&mut client, "").await;
+        exchange(&mut client, "EHLO client\r\n").await;
+        exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+        exchange(&mut client, "RCPT TO:<b@example.com>\r\n").await;
+        assert!(exchange(&mut client, "BDAT 5\r\nhello")
+            .await
+            .starts_with("250"));
+        // The chunk is discarded rather than buffered, until the client goes quiet
+        let reply = exchange(&mut client, "BDAT 18446744073709551614 LAST\r\nmore").await;
+        assert!(reply.starts_with("421"), "{}", reply);
+    }
+
+    #[test]
+    fn spooled_envelope_ends_before_the_message_headers() {
+        let failed = FailedForward {
+            error: ForwardError::EmptyMessage,
+            recipients: vec!["b@example.com".to_string()],
+            chats: vec![("-1002".to_string(), Some(7))],
+        };
+        let email = b"X-Spool-Chat: -1009\r\n\
+                      X-Spool-Mail-From: ceo@example.com\r\n\
+                      Subject: Hi\r\n\
+                      \r\n\
+                      Body\r\n";
+        let content = spool_file_content(email, Some("a@example.com"), &failed, 1700000000);
+        let spooled = parse_spooled_message(&content).unwrap();
+        assert_eq!(spooled.mail_from.as_deref(), Some("a@example.com"));
+        assert_eq!(spooled.recipients, ["b@example.com"]);
+        assert_eq!(spooled.chats, [("-1002".to_string(), Some(7))]);
+        assert_eq!(spooled.queued, 1700000000);
+        assert_eq!(spooled.data, email);
+    }
+
+    #[tokio::test]
+    async fn spooled_rewrite_keeps_the_file_and_first_failure_time() {
+        let dir =
+            std::env::temp_dir().join(format!("smtp-to-telegram-respool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let telegram_api = FakeTelegram::start(|request| {
+            match request.fields.get("chat_id").map(String::as_str) {
+                Some("-1002") => (
+                    502,
+                    r#"{"ok":false,"error_code":502,"description":"Bad Gateway"}"#.to_string(),
+                ),
+                _ => (200, FAKE_OK.to_string()),
+            }
+        })
+        .await;
+        let mut config = test_config();
+        config.telegram_chat_ids = vec!["-1001".to_string(), "-1002".to_string()];
+        config.spool = Some(Spool {
+            dir: dir.clone(),
+            max_age: Duration::from_secs(3600),
+            max_size: 1024 * 1024,
+        });
+        let telegram = telegram_api.client(config);
+        let queued = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 60;
+        let failed = FailedForward {
+            error: ForwardError::EmptyMessage,
+            recipients: vec!["b@example.com".to_string()],
+            chats: vec![("-1001".to_string(), None), ("-1002".to_string(), None)],
+        };
+        let email = b"Subject: Disk full\r\n\r\n/var is at 98%\r\n";
+        let path = dir.join("1-0.eml");
+        let content = spool_file_content(email, Some("a@example.com"), &failed, queued);
+        std::fs::write(&path, content).unwrap();
+
+        telegram.retry_spool().await.unwrap();
+        assert_eq!(
+            telegram_api.fields("sendMessage", "chat_id"),
+            ["-1001", "-1002"]
+        );
+        let content = std::fs::read(&path).unwrap();
+        let spooled = parse_spooled_message(&content).unwrap();
+        assert_eq!(spooled.chats, [("-1002".to_string(), None)]);
+        assert_eq!(spooled.queued, queued);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Past --spool-max-age from the first failure, whatever the file time says
+        let content = spool_file_content(email, Some("a@example.com"), &failed, queued - 7200);
+        std::fs::write(&path, content).unwrap();
+        telegram.retry_spool().await.unwrap();
+        assert_eq!(telegram_api.requests().len(), 2);
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_errors_dont_show_the_token() {
+        // Nothing listens on the port of a listener that was just dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let mut config = test_config();
+        config.api_base_url = base_url.clone();
+        config.dry_run = false;
+        config.max_send_attempts = 2;
+        let telegram = TelegramClient::new(Arc::new(reqwest::Client::new()), Arc::new(config));
+        let Err(failed) = forward(&telegram, b"Subject: Hi\r\n\r\nBody\r\n").await else {
+            panic!("nothing should be listening at {}", base_url);
+        };
+        assert!(matches!(failed.error, ForwardError::Network(_)));
+        let message = failed.error.to_string();
+        assert!(!message.contains("123:test"), "{}", message);
+
+        let error = check_bot_token(&reqwest::Client::new(), &base_url, "123:test")
+            .await
+            .unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(!message.contains("123:test"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn caption_rejected_formatting_is_sent_as_plain_text() {
+        let telegram_api = FakeTelegram::start(|request| {
+            if request.fields.contains_key("parse_mode") {
+                (
+                    400,
+                    r#"{"ok":false,"error_code":400,"description":"Bad Request: can't parse entities"}"#
+                        .to_string(),
+                )
+            } else {
+                (200, FAKE_OK.to_string())
+            }
+        })
+        .await;
+        let telegram = telegram_api.client(test_config());
+        let email = b"Subject: Chart\r\n\
+                      Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+                      \r\n\
+                      --b\r\n\
+                      Content-Type: text/html\r\n\
+                      \r\n\
+                      <p>Load is <b>high</b></p>\r\n\
+                      --b\r\n\
+                      Content-Type: image/png\r\n\
+                      Content-Disposition: attachment; filename=\"load.png\"\r\n\
+                      \r\n\
+                      PNG\r\n\
+                      --b--\r\n";
+        assert!(forward(&telegram, email).await.is_ok());
+
+        let requests = telegram_api.requests();
+        let methods: Vec<&str> = requests.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["sendPhoto", "sendPhoto"]);
+        assert_eq!(requests[0].fields["parse_mode"], "HTML");
+        assert!(requests[0].fields["caption"].contains("<b>high</b>"));
+        assert!(!requests[1].fields.contains_key("parse_mode"));
+        let caption = &requests[1].fields["caption"];
+        assert!(caption.contains("Load is high"), "{}", caption);
+    }
+
+    #[tokio::test]
+    async fn flood_notice_is_sent_when_the_storm_stops() {
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.flood_limit = 3;
+        config.flood_window = Duration::from_secs(1);
+        config.thread_id = Some(5);
+        let telegram = telegram_api.client(config);
+        for i in 1..=5 {
+            let alert = format!("Subject: Alert {}\r\n\r\nBody\r\n", i);
+            assert!(forward(&telegram, alert.as_bytes()).await.is_ok());
+        }
+
+        // Nothing is due while the window is still open
+        telegram.flush_flood_notices().await;
+        assert_eq!(telegram_api.fields("sendMessage", "text").len(), 3);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        telegram.flush_flood_notices().await;
+        telegram.flush_flood_notices().await;
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 4);
+        assert_eq!(
+            texts[3],
+            "⚠️ Suppressed 2 message(s) over the flood limit of 3 per 1 seconds"
+        );
+        assert_eq!(
+            telegram_api.fields("sendMessage", "message_thread_id")[3],
+            "5"
+        );
+
+        // The window was reset, the next message isn't preceded by the notice again
+        assert!(forward(&telegram, b"Subject: Later\r\n\r\nBody\r\n")
+            .await
+            .is_ok());
+        let texts = telegram_api.fields("sendMessage", "text");
+        assert_eq!(texts.len(), 5);
+        assert!(texts[4].contains("Subject: Later"), "{}", texts[4]);
+    }
+
+    #[tokio::test]
+    async fn created_topics_are_reused_after_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("smtp-to-telegram-topics-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recipients = ["ops@example.com".to_string()];
+        let report = |telegram: &TelegramClient, sender| {
+            let telegram = telegram.clone();
+            let recipients = recipients.clone();
+            async move {
+                telegram
+                    .forward_message(
+                        b"Subject: Report\r\n\r\nBody\r\n",
+                        Some(sender),
+                        &recipients,
+                        None,
+                    )
+                    .await
+                    .is_ok()
+            }
+        };
+
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.auto_create_topics = true;
+        config.topics_file = Some(path.clone());
+        let telegram = telegram_api.client(config);
+        // Concurrent mail from a new sender creates one topic
+        let (first, second) = tokio::join!(
+            report(&telegram, "New@example.com"),
+            report(&telegram, "new@example.com")
+        );
+        assert!(first && second);
+        assert_eq!(telegram_api.fields("createForumTopic", "name").len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "42\tnew@example.com\t77\n"
+        );
+
+        // After a restart the saved topic is used without creating another
+        let telegram_api = FakeTelegram::ok().await;
+        let mut config = test_config();
+        config.auto_create_topics = true;
+        config.created_topics = Mutex::new(load_created_topics(&path).unwrap());
+        config.topics_file = Some(path.clone());
+        let telegram = telegram_api.client(config);
+        assert!(report(&telegram, "new@example.com").await);
+        assert!(telegram_api.fields("createForumTopic", "name").is_empty());
+        assert_eq!(
+            telegram_api.fields("sendMessage", "message_thread_id"),
+            ["77"]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caption_limit_counts_visible_utf16_length() {
+        let with_photo = |content_type: &str, body: &str| {
+            format!(
+                "Subject: Chart\r\n\
+                 Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+                 \r\n\
+                 --b\r\n\
+                 Content-Type: {}; charset=utf-8\r\n\
+                 \r\n\
+                 {}\r\n\
+                 --b\r\n\
+                 Content-Type: image/png\r\n\
+                 Content-Disposition: attachment; filename=\"load.png\"\r\n\
+                 \r\n\
+                 PNG\r\n\
+                 --b--\r\n",
+                content_type, body
+            )
+        };
+
+        // Markup doesn't count, 1600 characters of HTML show 200
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        let email = with_photo("text/html", &"<b>x</b>".repeat(200));
+        assert!(forward(&telegram, email.as_bytes()).await.is_ok());
+        let methods: Vec<String> = telegram_api
+            .requests()
+            .into_iter()
+            .map(|r| r.method)
+            .collect();
+        assert_eq!(methods, ["sendPhoto"]);
+
+        // 600 emoji are 1200 UTF-16 code units, too long for a caption
+        let telegram_api = FakeTelegram::ok().await;
+        let telegram = telegram_api.client(test_config());
+        let email = with_photo("text/plain", &"\u{1F600}".repeat(600));
+        assert!(forward(&telegram, email.as_bytes()).await.is_ok());
+        let requests = telegram_api.requests();
+        let methods: Vec<&str> = requests.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["sendMessage", "sendPhoto"]);
+        assert!(!requests[1].fields.contains_key("caption"));
+    }
+}
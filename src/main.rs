@@ -1,100 +1,30 @@
 use anyhow::{Context, Result};
-use base64::{Engine as _, engine::general_purpose};
-use clap::Parser;
-use mail_parser::{MessageParser, MimeHeaders};
-use smtp_proto::Request;
-use smtp_proto::Response;
-use std::borrow::Cow;
-use std::collections::HashSet;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use chrono_tz::Tz;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn, Instrument};
 
-use ammonia::{Builder, UrlRelative};
-use once_cell::sync::Lazy;
+use serde::Deserialize;
 use std::collections::HashMap;
 
-static TELEGRAM_HTML_SANITIZER: Lazy<Builder<'static>> = Lazy::new(|| {
-    // Allowed tags according to Telegram HTML-style
-    let mut tags: HashSet<&'static str> = HashSet::new();
-    for t in [
-        "b",
-        "strong",
-        "i",
-        "em",
-        "u",
-        "ins",
-        "s",
-        "strike",
-        "del",
-        "span",
-        "tg-spoiler",
-        "a",
-        "code",
-        "pre",
-    ] {
-        tags.insert(t);
-    }
-
-    // Build tag attributes map
-    let mut tag_attrs: HashMap<&'static str, HashSet<&'static str>> = HashMap::new();
-    let mut a_attrs = HashSet::new();
-    a_attrs.insert("href");
-    tag_attrs.insert("a", a_attrs);
-
-    let mut span_attrs = HashSet::new();
-    span_attrs.insert("class");
-    tag_attrs.insert("span", span_attrs);
-
-    let mut code_attrs = HashSet::new();
-    code_attrs.insert("class");
-    tag_attrs.insert("code", code_attrs);
-
-    let mut pre_attrs = HashSet::new();
-    pre_attrs.insert("class");
-    tag_attrs.insert("pre", pre_attrs);
-
-    // Builder for sanitizer
-    let mut builder = Builder::default();
-
-    builder
-        // Allow only listed tags
-        .tags(tags)
-        // By default ammonia allows many attributes,
-        // here we reset everything and explicitly set what we need
-        .generic_attributes(HashSet::new())
-        .tag_attributes(tag_attrs)
-        // Don't touch relative URLs (Telegram will handle them)
-        .url_relative(UrlRelative::PassThrough)
-        // Don't add/change rel on links
-        .link_rel(None)
-        // Filter attribute values (keep only allowed ones)
-        .attribute_filter(|tag, attr, value| {
-            match (tag, attr) {
-                // Allow only class="tg-spoiler" on <span>
-                ("span", "class") => {
-                    let has_spoiler = value.split_whitespace().any(|cls| cls == "tg-spoiler");
-                    if has_spoiler {
-                        // Telegram only needs one tg-spoiler class
-                        Some(Cow::Borrowed("tg-spoiler"))
-                    } else {
-                        None
-                    }
-                }
-                // On <code>/<pre> keep only class="language-..."
-                ("code", "class") | ("pre", "class") => {
-                    if value.starts_with("language-") {
-                        Some(Cow::Borrowed(value))
-                    } else {
-                        None
-                    }
-                }
-                // Other allowed attributes keep as is
-                _ => Some(Cow::Borrowed(value)),
-            }
-        });
-
-    builder
-});
+use smtp_to_telegram::{
+    check_bot_token, flush_flood_notices, load_created_topics, load_tls_acceptor, mask_token,
+    parse_button, parse_chat_id, parse_cidr, parse_route, parse_sender_topic, read_proxy_header,
+    read_token_file, resolve_chat_id, resolve_include_headers, retry_spooled_messages,
+    system_hostname, AttachEml, BodyTransform, Button, Cidr, Metrics, ParseMode, PreferredPart,
+    Route, SeenMessages, SenderTopic, SessionConfig, SmtpSession, SmtpStream, Spool,
+    TelegramClient, TokenBucket, DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_SPLIT_LOOKBACK,
+    DEFAULT_TELEGRAM_API_URL, DEFAULT_TEMPLATE, MAX_DOCUMENT_SIZE, MAX_LOCAL_DOCUMENT_SIZE,
+    MIN_MAX_MESSAGE_LENGTH, TEMPLATE_PLACEHOLDERS, TEMPLATE_PLACEHOLDER_RE,
+};
 
 const COPYRIGHT: &str = "Decker + ChatGPT/Cursor/Manus";
 
@@ -116,808 +46,1745 @@ const VERSION_WITH_COPYRIGHT: &str = concat!(
         "Forwards email messages received via SMTP to a Telegram bot.\n\n",
         "Copyright (c) Decker + ChatGPT/Cursor/Manus"
     ),
-    override_usage = "smtp-to-telegram --token <TOKEN> --chat-id <CHAT_ID> [--port <PORT>] [--bind <ADDRESS>]"
+    override_usage = "smtp-to-telegram <--token <TOKEN>|--token-file <PATH>> [--chat-id <CHAT_ID>] [--route <PATTERN=CHAT_ID>]... [OPTIONS]"
 )]
 struct Args {
-    /// Telegram Bot Token
-    #[arg(short, long, env = "TELEGRAM_TOKEN")]
-    token: String,
+    /// TOML file with default values for the options below (flags and environment take precedence)
+    #[arg(long, value_name = "PATH", env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
 
-    /// Telegram Chat ID
-    #[arg(short, long, env = "TELEGRAM_CHAT_ID")]
-    chat_id: String,
+    /// Telegram Bot Token
+    #[arg(
+        short,
+        long,
+        env = "TELEGRAM_TOKEN",
+        required_unless_present = "token_file",
+        conflicts_with = "token_file"
+    )]
+    token: Option<String>,
+
+    /// File containing the Telegram Bot Token, re-read on SIGHUP to rotate the token
+    #[arg(long, value_name = "PATH", env = "TELEGRAM_TOKEN_FILE")]
+    token_file: Option<PathBuf>,
+
+    /// Telegram Chat ID (default destination when no route matches), a number or @username
+    /// (repeatable or comma-separated, every chat gets the message)
+    #[arg(
+        short,
+        long,
+        env = "TELEGRAM_CHAT_ID",
+        value_delimiter = ',',
+        allow_negative_numbers = true,
+        value_parser = parse_chat_id
+    )]
+    chat_id: Vec<String>,
+
+    /// Look up the numeric ID of @username chats with getChat at startup and send to it
+    #[arg(long, env = "RESOLVE_CHAT_ID")]
+    resolve_chat_id: bool,
+
+    /// Base URL of the Bot API server, e.g. a self-hosted one
+    #[arg(
+        long,
+        value_name = "URL",
+        default_value = DEFAULT_TELEGRAM_API_URL,
+        env = "TELEGRAM_API_BASE_URL"
+    )]
+    api_base_url: String,
+
+    /// Proxy for requests to the Bot API: `http://`, `https://` or `socks5://` URL
+    #[arg(long, value_name = "URL", env = "HTTPS_PROXY")]
+    proxy: Option<String>,
+
+    /// Forum topic (message_thread_id) in --chat-id to post to instead of the general topic
+    #[arg(long, env = "TELEGRAM_THREAD_ID")]
+    thread_id: Option<i64>,
+
+    /// Route recipients to a chat: `PATTERN=CHAT_ID[:THREAD_ID]`, where PATTERN is an address
+    /// or a wildcard like `*@ops.example.com` (repeatable, first match wins)
+    #[arg(
+        long = "route",
+        value_name = "PATTERN=CHAT_ID[:THREAD_ID]",
+        env = "ROUTES",
+        value_delimiter = ',',
+        value_parser = parse_route
+    )]
+    routes: Vec<Route>,
 
     /// SMTP server port
     #[arg(short, long, default_value = "2525", env = "SMTP_PORT")]
     port: u16,
 
-    /// Bind address for SMTP server
-    #[arg(short, long, default_value = "0.0.0.0", env = "SMTP_BIND")]
-    bind: String,
+    /// Hostname announced in the 220 greeting and the EHLO reply (default: the system hostname)
+    #[arg(long, env = "SMTP_HOSTNAME")]
+    hostname: Option<String>,
+
+    /// Bind addresses for SMTP server, e.g. `0.0.0.0,::` for IPv4 and IPv6
+    /// (repeatable or comma-separated)
+    #[arg(
+        short,
+        long,
+        default_value = "0.0.0.0",
+        env = "SMTP_BIND",
+        value_delimiter = ','
+    )]
+    bind: Vec<std::net::IpAddr>,
+
+    /// User name or uid to switch to once the listeners are bound, e.g. when started as
+    /// root to bind port 25
+    #[arg(long, value_name = "USER", env = "RUN_AS_USER")]
+    user: Option<String>,
+
+    /// Group name or gid to switch to once the listeners are bound (default: the primary
+    /// group of --user)
+    #[arg(long, value_name = "GROUP", env = "RUN_AS_GROUP")]
+    group: Option<String>,
+
+    /// Post mail from a sender to a forum topic: `PATTERN=THREAD_ID`, where PATTERN is an
+    /// envelope sender address or wildcard (repeatable, first match wins)
+    #[arg(
+        long = "sender-topic",
+        value_name = "PATTERN=THREAD_ID",
+        env = "SENDER_TOPICS",
+        value_delimiter = ',',
+        value_parser = parse_sender_topic
+    )]
+    sender_topics: Vec<SenderTopic>,
+
+    /// Only forward mail from these envelope senders, exact addresses or wildcards like
+    /// `*@example.com` (repeatable or comma-separated)
+    #[arg(
+        long = "from-allow",
+        value_name = "PATTERN",
+        env = "FROM_ALLOW",
+        value_delimiter = ','
+    )]
+    from_allow: Vec<String>,
+
+    /// Don't forward mail from these envelope senders, exact addresses or wildcards like
+    /// `noreply@*` (repeatable or comma-separated)
+    #[arg(
+        long = "from-block",
+        value_name = "PATTERN",
+        env = "FROM_BLOCK",
+        value_delimiter = ','
+    )]
+    from_block: Vec<String>,
+
+    /// Only forward mail whose subject matches this regular expression, e.g. `(?i)alert`
+    #[arg(
+        long,
+        value_name = "REGEX",
+        env = "SUBJECT_REGEX",
+        value_parser = regex::Regex::new
+    )]
+    subject_regex: Option<regex::Regex>,
+
+    /// Only accept SMTP connections from these networks, e.g. `10.0.0.0/8` or `2001:db8::/32`
+    /// (repeatable or comma-separated, all addresses are allowed when unset)
+    #[arg(
+        long = "allow-cidr",
+        value_name = "CIDR",
+        env = "ALLOW_CIDRS",
+        value_delimiter = ',',
+        value_parser = parse_cidr
+    )]
+    allow_cidrs: Vec<Cidr>,
+
+    /// Expect a PROXY protocol v1 or v2 header on every connection, as sent by HAProxy or a
+    /// cloud load balancer, and use the client address it carries for logging and --allow-cidr
+    #[arg(long, env = "PROXY_PROTOCOL")]
+    proxy_protocol: bool,
+
+    /// Add an inline keyboard URL button to forwarded messages: `LABEL=URL` (repeatable).
+    /// The URL may contain {subject}, {from} and {to}, which are filled in URL-encoded
+    #[arg(
+        long = "button",
+        value_name = "LABEL=URL",
+        env = "BUTTONS",
+        value_delimiter = ';',
+        value_parser = parse_button
+    )]
+    buttons: Vec<Button>,
+
+    /// Create a forum topic named after each sender without a --sender-topic mapping
+    /// (the chat must be a forum and the bot needs the Manage Topics right)
+    #[arg(long, env = "AUTO_CREATE_TOPICS")]
+    auto_create_topics: bool,
+
+    /// File the topics created with --auto-create-topics are saved to, so that they are
+    /// reused after a restart
+    #[arg(long, value_name = "PATH", env = "TOPICS_FILE")]
+    topics_file: Option<PathBuf>,
+
+    /// Extra email header to show in forwarded messages (repeatable or comma-separated).
+    /// The `audit` preset expands to the delivery routing headers (Delivered-To, X-Original-To, ...)
+    #[arg(
+        long = "include-header",
+        value_name = "HEADER",
+        env = "INCLUDE_HEADERS",
+        value_delimiter = ','
+    )]
+    include_headers: Vec<String>,
+
+    /// TLS certificate chain (PEM) enabling STARTTLS
+    #[arg(long, value_name = "PATH", env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// TLS private key (PEM) enabling STARTTLS
+    #[arg(long, value_name = "PATH", env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Also listen on this port for implicit TLS (SMTPS) connections, usually 465, using the
+    /// --tls-cert certificate
+    #[arg(long, value_name = "PORT", env = "TLS_PORT", requires = "tls_cert")]
+    tls_port: Option<u16>,
+
+    /// Username SMTP clients must authenticate with (AUTH PLAIN/LOGIN)
+    #[arg(long, env = "SMTP_AUTH_USER", requires = "auth_pass")]
+    auth_user: Option<String>,
+
+    /// Password SMTP clients must authenticate with
+    #[arg(
+        long,
+        env = "SMTP_AUTH_PASS",
+        requires = "auth_user",
+        hide_env_values = true
+    )]
+    auth_pass: Option<String>,
+
+    /// Parse mode for emails that aren't text/html (HTML emails always use HTML)
+    #[arg(long, env = "PARSE_MODE", value_enum, default_value_t = ParseMode::None)]
+    parse_mode: ParseMode,
+
+    /// Turn bare http(s) URLs in plain text emails into links (requires --parse-mode html)
+    #[arg(long, env = "LINKIFY")]
+    linkify: bool,
+
+    /// Time zone for the Date line of forwarded messages, as an IANA name (e.g. Europe/Berlin)
+    #[arg(long, env = "TIMEZONE", default_value = "UTC")]
+    timezone: Tz,
+
+    /// Show the List-Unsubscribe links of newsletters as an "Unsubscribe:" line
+    #[arg(long, env = "SHOW_UNSUBSCRIBE")]
+    show_unsubscribe: bool,
+
+    /// Transform applied to the email body, in the given order (repeatable or comma-separated)
+    #[arg(
+        long = "transform",
+        value_name = "NAME",
+        env = "TRANSFORMS",
+        value_enum,
+        value_delimiter = ','
+    )]
+    transforms: Vec<BodyTransform>,
+
+    /// Let emails choose their parse mode with an X-Telegram-Parse-Mode header (HTML,
+    /// MarkdownV2, Markdown or None); the body is then sent as written
+    #[arg(long, env = "ALLOW_PARSE_MODE_HEADER")]
+    allow_parse_mode_header: bool,
+
+    /// Show SPF/DKIM/DMARC results from Authentication-Results/Received-SPF as a summary line
+    #[arg(long, env = "SHOW_AUTH_RESULTS")]
+    show_auth_results: bool,
+
+    /// Forward the body with its original indentation and blank lines instead of trimming
+    /// every line
+    #[arg(long, env = "NO_WHITESPACE_CLEANUP")]
+    no_whitespace_cleanup: bool,
+
+    /// Repeat the subject and a short message id in the "[part i of n]" prefix of every part
+    /// of a split message, so that parts delivered out of order can be matched up
+    #[arg(long, env = "CHUNK_HEADER")]
+    chunk_header: bool,
+
+    /// Show the From/To headers with display names instead of the envelope addresses
+    /// (the envelope is used when a header is missing)
+    #[arg(long, env = "PREFER_HEADER_ADDRESSES")]
+    prefer_header_addresses: bool,
+
+    /// Part of multipart emails to forward: the HTML part, converted for Telegram, or the
+    /// plain text part (the other part is used when the preferred one is missing)
+    #[arg(long, env = "PREFER", value_enum, default_value_t = PreferredPart::Html)]
+    prefer: PreferredPart,
+
+    /// Same as --prefer text
+    #[arg(long, env = "PREFER_PLAINTEXT")]
+    prefer_plaintext: bool,
+
+    /// Drop quoted reply lines ("> ...") and the signature after a "-- " line from plain text
+    /// bodies
+    #[arg(long, env = "STRIP_QUOTES")]
+    strip_quotes: bool,
+
+    /// Don't show link previews in forwarded messages
+    #[arg(long, env = "NO_LINK_PREVIEW")]
+    no_link_preview: bool,
+
+    /// Send messages and attachments without a notification sound
+    #[arg(long, env = "SILENT")]
+    silent: bool,
+
+    /// Forward emails without any text as a placeholder instead of dropping them.
+    /// `{from}` is replaced with the envelope sender
+    #[arg(
+        long,
+        value_name = "TEXT",
+        num_args = 0..=1,
+        default_missing_value = "(empty email from {from})",
+        env = "FORWARD_EMPTY_AS_PLACEHOLDER"
+    )]
+    forward_empty_as_placeholder: Option<String>,
+
+    /// Upload the raw email as message.eml with every email (always) or only with emails
+    /// without any text (empty), to diagnose messages that weren't extracted well
+    #[arg(long, value_name = "WHEN", env = "ATTACH_EML", value_enum)]
+    attach_eml: Option<AttachEml>,
+
+    /// Layout of forwarded messages with the placeholders {from}, {to}, {subject}, {date},
+    /// {headers} and {body}; `\n` starts a new line (default: From and To lines, the
+    /// headers, then the body)
+    #[arg(long, env = "MESSAGE_TEMPLATE")]
+    template: Option<String>,
+
+    /// Maximum email size in bytes, advertised with the SIZE extension
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "26214400",
+        env = "MAX_MESSAGE_SIZE"
+    )]
+    max_message_size: usize,
+
+    /// Longest text sent in one Telegram message, in characters; longer text is split into
+    /// parts (at least 256, a self-hosted Bot API server may allow more than 4096)
+    #[arg(
+        long,
+        value_name = "CHARS",
+        default_value_t = DEFAULT_MAX_MESSAGE_LENGTH,
+        env = "MAX_MESSAGE_LENGTH"
+    )]
+    max_message_length: usize,
+
+    /// How far back from --max-message-length to look for a blank line, then a line break,
+    /// then a space to split at, in characters
+    #[arg(
+        long,
+        value_name = "CHARS",
+        default_value_t = DEFAULT_SPLIT_LOOKBACK,
+        env = "SPLIT_LOOKBACK"
+    )]
+    split_lookback: usize,
+
+    /// Upload text longer than --max-message-length as a message.txt document with its
+    /// opening lines as the caption, instead of splitting it into parts
+    #[arg(long, env = "LONG_AS_DOCUMENT")]
+    long_as_document: bool,
+
+    /// Milliseconds to wait before the greeting; clients talking earlier get 554 (0 disables)
+    #[arg(long, value_name = "MS", default_value = "0", env = "GREETING_DELAY")]
+    greeting_delay: u64,
+
+    /// Maximum number of RCPT TO recipients per message; further ones are refused with 452
+    #[arg(long, default_value = "100", env = "MAX_RECIPIENTS")]
+    max_recipients: usize,
+
+    /// Maximum number of simultaneous SMTP connections, more are refused with 421
+    #[arg(long, default_value = "100", env = "MAX_CONNECTIONS")]
+    max_connections: usize,
+
+    /// Seconds to wait for a line from the client before closing the connection with 421
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "300",
+        env = "IO_TIMEOUT"
+    )]
+    io_timeout: u64,
+
+    /// Maximum number of Telegram API requests in flight across all sessions
+    #[arg(long, default_value = "4", env = "MAX_TELEGRAM_CONCURRENCY")]
+    max_telegram_concurrency: usize,
+
+    /// Attempts per Telegram request when it fails with 429, 5xx or a connection error
+    #[arg(long, default_value = "5", env = "MAX_SEND_ATTEMPTS")]
+    max_send_attempts: u32,
+
+    /// Reply to DATA with 451 (temporary) or 550 (permanent) when the message couldn't be
+    /// forwarded to Telegram, instead of accepting it with 250
+    #[arg(long, env = "CONFIRM_DELIVERY")]
+    confirm_delivery: bool,
+
+    /// Maximum number of messages forwarded to a chat per --flood-window; further messages
+    /// are dropped and counted in a notice (0 disables the limit)
+    #[arg(long, default_value = "0", env = "FLOOD_LIMIT")]
+    flood_limit: u32,
+
+    /// Length of the --flood-limit window in seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        env = "FLOOD_WINDOW"
+    )]
+    flood_window: u64,
+
+    /// Seconds during which a message with the same Message-ID and recipients as a forwarded
+    /// one is accepted but not forwarded again (0 disables deduplication)
+    #[arg(long, value_name = "SECONDS", default_value = "0", env = "DEDUPE_TTL")]
+    dedupe_ttl: u64,
+
+    /// Directory where messages that still fail after all send attempts are kept and
+    /// retried every minute until Telegram takes them
+    #[arg(long, value_name = "PATH", env = "SPOOL_DIR")]
+    spool_dir: Option<PathBuf>,
+
+    /// Seconds after which a spooled message is given up on and deleted
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "86400",
+        env = "SPOOL_MAX_AGE"
+    )]
+    spool_max_age: u64,
+
+    /// Maximum total size of the spool in bytes; failed messages that don't fit are dropped
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "104857600",
+        env = "SPOOL_MAX_SIZE"
+    )]
+    spool_max_size: u64,
+
+    /// Maximum Telegram API requests per second across all chats (0 disables the limit)
+    #[arg(
+        long,
+        value_name = "PER_SECOND",
+        default_value = "30",
+        env = "RATE_LIMIT"
+    )]
+    rate_limit: f64,
+
+    /// Maximum Telegram API requests per second to one chat, e.g. 0.33 for the 20 messages
+    /// per minute Telegram allows in groups (0 disables the limit)
+    #[arg(
+        long,
+        value_name = "PER_SECOND",
+        default_value = "1",
+        env = "CHAT_RATE_LIMIT"
+    )]
+    chat_rate_limit: f64,
+
+    /// Milliseconds to wait between the parts of a split message, on top of the rate limits
+    #[arg(long, value_name = "MS", default_value = "100", env = "CHUNK_DELAY_MS")]
+    chunk_delay_ms: u64,
+
+    /// Serve Prometheus metrics on `/metrics` at this address, e.g. `127.0.0.1:9090`
+    #[arg(long, value_name = "ADDR:PORT", env = "METRICS_BIND")]
+    metrics_bind: Option<std::net::SocketAddr>,
+
+    /// Serve a health check on `/healthz` at this address, e.g. `0.0.0.0:8080`
+    #[arg(long, value_name = "ADDR:PORT", env = "HEALTH_BIND")]
+    health_bind: Option<std::net::SocketAddr>,
+
+    /// Don't call getMe at startup to check the bot token, e.g. for offline testing
+    #[arg(long, env = "SKIP_STARTUP_CHECK")]
+    skip_startup_check: bool,
+
+    /// Log the messages that would be sent, with their chat and parse mode, instead of
+    /// calling the Telegram API (implies --skip-startup-check)
+    #[arg(long, env = "DRY_RUN")]
+    dry_run: bool,
+
+    /// Log filter, a level (error, warn, info, debug, trace) or tracing directives such as
+    /// `smtp_to_telegram=debug`
+    #[arg(long, value_name = "FILTER", default_value = "info", env = "RUST_LOG")]
+    log_level: String,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value = "text", env = "LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Seconds to wait for active sessions to finish on shutdown
+    #[arg(long, default_value = "30", env = "SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: u64,
+
+    /// File to write the process ID to, removed again on a clean shutdown
+    #[arg(long, value_name = "PATH", env = "PID_FILE")]
+    pid_file: Option<PathBuf>,
 }
 
-#[derive(Clone, Copy)]
-enum AuthState {
-    None,
-    LoginWaitingUsername,
-    LoginWaitingPassword,
-    PlainWaitingData,
+// Options loaded from the --config file, named like the Args fields
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    token: Option<String>,
+    token_file: Option<String>,
+    chat_id: Option<String>,
+    resolve_chat_id: Option<bool>,
+    api_base_url: Option<String>,
+    proxy: Option<String>,
+    thread_id: Option<i64>,
+    routes: Option<Vec<String>>,
+    sender_topics: Option<Vec<String>>,
+    from_allow: Option<Vec<String>>,
+    from_block: Option<Vec<String>>,
+    subject_regex: Option<String>,
+    buttons: Option<Vec<String>>,
+    allow_cidrs: Option<Vec<String>>,
+    proxy_protocol: Option<bool>,
+    auto_create_topics: Option<bool>,
+    topics_file: Option<String>,
+    port: Option<u16>,
+    hostname: Option<String>,
+    bind: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    include_headers: Option<Vec<String>>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_port: Option<u16>,
+    auth_user: Option<String>,
+    auth_pass: Option<String>,
+    parse_mode: Option<String>,
+    linkify: Option<bool>,
+    transforms: Option<Vec<String>>,
+    allow_parse_mode_header: Option<bool>,
+    show_auth_results: Option<bool>,
+    no_whitespace_cleanup: Option<bool>,
+    chunk_header: Option<bool>,
+    prefer_header_addresses: Option<bool>,
+    prefer: Option<String>,
+    prefer_plaintext: Option<bool>,
+    strip_quotes: Option<bool>,
+    no_link_preview: Option<bool>,
+    silent: Option<bool>,
+    timezone: Option<String>,
+    show_unsubscribe: Option<bool>,
+    forward_empty_as_placeholder: Option<String>,
+    attach_eml: Option<String>,
+    template: Option<String>,
+    max_message_size: Option<usize>,
+    max_message_length: Option<usize>,
+    split_lookback: Option<usize>,
+    long_as_document: Option<bool>,
+    io_timeout: Option<u64>,
+    greeting_delay: Option<u64>,
+    max_recipients: Option<usize>,
+    max_connections: Option<usize>,
+    max_telegram_concurrency: Option<usize>,
+    max_send_attempts: Option<u32>,
+    confirm_delivery: Option<bool>,
+    flood_limit: Option<u32>,
+    flood_window: Option<u64>,
+    dedupe_ttl: Option<u64>,
+    spool_dir: Option<String>,
+    spool_max_age: Option<u64>,
+    spool_max_size: Option<u64>,
+    rate_limit: Option<f64>,
+    chat_rate_limit: Option<f64>,
+    chunk_delay_ms: Option<u64>,
+    metrics_bind: Option<String>,
+    health_bind: Option<String>,
+    skip_startup_check: Option<bool>,
+    dry_run: Option<bool>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    shutdown_timeout: Option<u64>,
+    pid_file: Option<String>,
 }
 
-struct SmtpSession {
-    stream: TcpStream,
-    telegram_token: String,
-    telegram_chat_id: String,
-    buffer: Vec<u8>,
-    auth_state: AuthState,
+impl FileConfig {
+    fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    // Values keyed by Args field, applied as argument defaults so that flags and
+    // environment variables still override them
+    fn into_defaults(self) -> Vec<(&'static str, Vec<String>)> {
+        let single = [
+            ("token", self.token),
+            ("token_file", self.token_file),
+            (
+                "auto_create_topics",
+                self.auto_create_topics.map(|v| v.to_string()),
+            ),
+            ("topics_file", self.topics_file),
+            ("proxy_protocol", self.proxy_protocol.map(|v| v.to_string())),
+            ("chat_id", self.chat_id),
+            (
+                "resolve_chat_id",
+                self.resolve_chat_id.map(|v| v.to_string()),
+            ),
+            ("api_base_url", self.api_base_url),
+            ("proxy", self.proxy),
+            ("thread_id", self.thread_id.map(|v| v.to_string())),
+            ("port", self.port.map(|v| v.to_string())),
+            ("hostname", self.hostname),
+            ("bind", self.bind),
+            ("subject_regex", self.subject_regex),
+            ("user", self.user),
+            ("group", self.group),
+            ("tls_cert", self.tls_cert),
+            ("tls_key", self.tls_key),
+            ("tls_port", self.tls_port.map(|v| v.to_string())),
+            ("auth_user", self.auth_user),
+            ("auth_pass", self.auth_pass),
+            ("parse_mode", self.parse_mode),
+            ("linkify", self.linkify.map(|v| v.to_string())),
+            (
+                "allow_parse_mode_header",
+                self.allow_parse_mode_header.map(|v| v.to_string()),
+            ),
+            (
+                "show_auth_results",
+                self.show_auth_results.map(|v| v.to_string()),
+            ),
+            (
+                "no_whitespace_cleanup",
+                self.no_whitespace_cleanup.map(|v| v.to_string()),
+            ),
+            ("chunk_header", self.chunk_header.map(|v| v.to_string())),
+            (
+                "prefer_header_addresses",
+                self.prefer_header_addresses.map(|v| v.to_string()),
+            ),
+            ("prefer", self.prefer),
+            (
+                "prefer_plaintext",
+                self.prefer_plaintext.map(|v| v.to_string()),
+            ),
+            ("strip_quotes", self.strip_quotes.map(|v| v.to_string())),
+            (
+                "no_link_preview",
+                self.no_link_preview.map(|v| v.to_string()),
+            ),
+            ("silent", self.silent.map(|v| v.to_string())),
+            ("timezone", self.timezone),
+            (
+                "show_unsubscribe",
+                self.show_unsubscribe.map(|v| v.to_string()),
+            ),
+            (
+                "forward_empty_as_placeholder",
+                self.forward_empty_as_placeholder,
+            ),
+            ("attach_eml", self.attach_eml),
+            ("template", self.template),
+            (
+                "max_message_size",
+                self.max_message_size.map(|v| v.to_string()),
+            ),
+            (
+                "max_message_length",
+                self.max_message_length.map(|v| v.to_string()),
+            ),
+            ("split_lookback", self.split_lookback.map(|v| v.to_string())),
+            (
+                "long_as_document",
+                self.long_as_document.map(|v| v.to_string()),
+            ),
+            ("io_timeout", self.io_timeout.map(|v| v.to_string())),
+            ("greeting_delay", self.greeting_delay.map(|v| v.to_string())),
+            ("max_recipients", self.max_recipients.map(|v| v.to_string())),
+            (
+                "max_connections",
+                self.max_connections.map(|v| v.to_string()),
+            ),
+            (
+                "max_telegram_concurrency",
+                self.max_telegram_concurrency.map(|v| v.to_string()),
+            ),
+            (
+                "max_send_attempts",
+                self.max_send_attempts.map(|v| v.to_string()),
+            ),
+            (
+                "confirm_delivery",
+                self.confirm_delivery.map(|v| v.to_string()),
+            ),
+            ("flood_limit", self.flood_limit.map(|v| v.to_string())),
+            ("flood_window", self.flood_window.map(|v| v.to_string())),
+            ("dedupe_ttl", self.dedupe_ttl.map(|v| v.to_string())),
+            ("spool_dir", self.spool_dir),
+            ("spool_max_age", self.spool_max_age.map(|v| v.to_string())),
+            ("spool_max_size", self.spool_max_size.map(|v| v.to_string())),
+            ("rate_limit", self.rate_limit.map(|v| v.to_string())),
+            (
+                "chat_rate_limit",
+                self.chat_rate_limit.map(|v| v.to_string()),
+            ),
+            ("chunk_delay_ms", self.chunk_delay_ms.map(|v| v.to_string())),
+            ("metrics_bind", self.metrics_bind),
+            ("health_bind", self.health_bind),
+            (
+                "skip_startup_check",
+                self.skip_startup_check.map(|v| v.to_string()),
+            ),
+            ("dry_run", self.dry_run.map(|v| v.to_string())),
+            ("log_level", self.log_level),
+            ("log_format", self.log_format),
+            (
+                "shutdown_timeout",
+                self.shutdown_timeout.map(|v| v.to_string()),
+            ),
+            ("pid_file", self.pid_file),
+        ];
+        let multiple = [
+            ("routes", self.routes),
+            ("sender_topics", self.sender_topics),
+            ("from_allow", self.from_allow),
+            ("from_block", self.from_block),
+            ("buttons", self.buttons),
+            ("allow_cidrs", self.allow_cidrs),
+            ("include_headers", self.include_headers),
+            ("transforms", self.transforms),
+        ];
+
+        single
+            .into_iter()
+            .filter_map(|(id, value)| value.map(|v| (id, vec![v])))
+            .chain(
+                multiple
+                    .into_iter()
+                    .filter_map(|(id, values)| values.map(|v| (id, v))),
+            )
+            .collect()
+    }
 }
 
-impl SmtpSession {
-    fn new(stream: TcpStream, telegram_token: String, telegram_chat_id: String) -> Self {
-        Self {
-            stream,
-            telegram_token,
-            telegram_chat_id,
-            buffer: Vec::new(),
-            auth_state: AuthState::None,
+// Parses the command line, using values from the --config file as defaults
+fn parse_args() -> Result<Args> {
+    // First pass only looks up the config file, required options may come from it
+    let config_path = Args::command()
+        .ignore_errors(true)
+        .get_matches()
+        .get_one::<PathBuf>("config")
+        .cloned();
+
+    let mut command = Args::command();
+    if let Some(path) = &config_path {
+        for (id, values) in FileConfig::load(path)?.into_defaults() {
+            command = command.mut_arg(id, |arg| {
+                // Defaults may hold secrets, keep them out of --help
+                let takes_values = arg.get_action().takes_values();
+                arg.default_values(values)
+                    .hide_default_value(takes_values)
+                    .required(false)
+            });
         }
     }
 
-    async fn send_response(&mut self, response: Response<String>) -> Result<()> {
-        let mut buf = Vec::new();
-        response
-            .write(&mut buf)
-            .context("Failed to format response")?;
-        self.stream
-            .write_all(&buf)
-            .await
-            .context("Failed to write response")?;
-        Ok(())
+    Ok(Args::from_arg_matches(&command.get_matches())?)
+}
+
+// Format of log output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+// Installs the global tracing subscriber. Sessions log inside a span with their id and peer
+// address, which both formats include with every event.
+fn init_logging(filter: &str, format: LogFormat) -> Result<()> {
+    let filter = tracing_subscriber::EnvFilter::try_new(filter)
+        .context(format!("Invalid log level: {}", filter))?;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(io::stdout().is_terminal());
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
     }
+    Ok(())
+}
 
-    async fn read_line_bytes(&mut self) -> Result<Vec<u8>> {
-        let mut buf = [0u8; 1];
-        let mut line = Vec::new();
+// Binds an SMTP listener. An IPv6 socket keeps the OS default, which usually accepts IPv4
+// clients too, unless v6_only is set because IPv4 is bound separately.
+fn bind_listener(addr: std::net::SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    if addr.is_ipv6() && v6_only {
+        socket.set_only_v6(true)?;
+    }
+    // Same as TcpListener::bind, so a restart doesn't wait for TIME_WAIT sockets
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
 
-        loop {
-            let n = self
-                .stream
-                .read_exact(&mut buf)
-                .await
-                .context("Failed to read from stream")?;
+// Switches to an unprivileged user and group, e.g. after binding port 25 as root.
+// Supplementary groups are cleared and the group is changed first, while still allowed.
+#[cfg(unix)]
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    use nix::unistd::{Gid, Group, Uid, User};
+
+    let user = user
+        .map(|user| {
+            let found = match user.parse() {
+                Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+                Err(_) => User::from_name(user),
+            };
+            found
+                .context(format!("Failed to look up user {}", user))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown user: {}", user))
+        })
+        .transpose()?;
+    let gid = match group {
+        Some(group) => {
+            let found = match group.parse() {
+                Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+                Err(_) => Group::from_name(group),
+            };
+            found
+                .context(format!("Failed to look up group {}", group))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?
+                .gid
+        }
+        None => match &user {
+            Some(user) => user.gid,
+            None => return Ok(()),
+        },
+    };
+
+    #[cfg(not(target_vendor = "apple"))]
+    nix::unistd::setgroups(&[gid]).context("Failed to clear supplementary groups")?;
+    nix::unistd::setgid(gid).context(format!("Failed to switch to group {}", gid))?;
+    if let Some(user) = &user {
+        nix::unistd::setuid(user.uid).context(format!("Failed to switch to user {}", user.name))?;
+        info!(
+            "Running as user {} (uid {}, gid {})",
+            user.name, user.uid, gid
+        );
+    } else {
+        info!("Running as gid {}", gid);
+    }
+    Ok(())
+}
 
-            if n == 0 {
-                return Err(anyhow::anyhow!("Connection closed"));
+// Writes the process ID to a PID file. A file left by a crashed run is replaced, one of a
+// process that is still running is an error.
+fn write_pid_file(path: &Path) -> Result<()> {
+    if path.exists() {
+        #[cfg(unix)]
+        if let Some(pid) = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| contents.trim().parse::<i32>().ok())
+        {
+            use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+            // Signal 0 only checks the process exists; EPERM means it belongs to someone else
+            if pid > 0 && kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH) {
+                return Err(anyhow::anyhow!(
+                    "PID file {} belongs to running process {}",
+                    path.display(),
+                    pid
+                ));
             }
+        }
+        info!("Replacing stale PID file {}", path.display());
+    }
+    std::fs::write(path, format!("{}\n", std::process::id()))
+        .context(format!("Failed to write PID file {}", path.display()))
+}
 
-            line.push(buf[0]);
+// How often the health endpoint re-runs the getMe check
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
-            if line.len() >= 2 && line[line.len() - 2] == b'\r' && line[line.len() - 1] == b'\n' {
-                return Ok(line);
-            }
+// State reported on --health-bind
+struct Health {
+    accepting: AtomicBool,
+    // Outcome of the last getMe check
+    telegram_ok: AtomicBool,
+}
+
+impl Health {
+    fn status(&self) -> (u16, String) {
+        if !self.accepting.load(Ordering::Relaxed) {
+            (503, "Not accepting connections\n".to_string())
+        } else if !self.telegram_ok.load(Ordering::Relaxed) {
+            (503, "Telegram getMe check failed\n".to_string())
+        } else {
+            (200, "OK\n".to_string())
         }
     }
+}
 
-    async fn send_to_telegram(&self, text: &str, parse_mode: Option<&str>) -> Result<()> {
-        self.send_to_telegram_internal(text, parse_mode).await
+// Re-runs the getMe check for --health-bind, so a revoked token or lost connectivity to
+// Telegram makes the service unhealthy
+async fn monitor_telegram(
+    http_client: Arc<reqwest::Client>,
+    config: Arc<SessionConfig>,
+    health: Arc<Health>,
+) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    // The first tick completes right away, the startup check has just run
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let token = config.telegram_token();
+        let ok = match check_bot_token(&http_client, &config.api_base_url, &token).await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Telegram health check failed: {:#}", e);
+                false
+            }
+        };
+        health.telegram_ok.store(ok, Ordering::Relaxed);
     }
+}
 
-    async fn send_to_telegram_internal(&self, text: &str, parse_mode: Option<&str>) -> Result<()> {
-        // Telegram API limit: 1-4096 characters after entities parsing
-        const MAX_MESSAGE_LENGTH: usize = 4096;
+// Answers HTTP GET requests on the listener with the handler's status and body for the path,
+// closing each connection after one response. Enough for scrapers and probes without
+// pulling in an HTTP server.
+async fn serve_http<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = respond_http(stream, handler.as_ref()).await {
+                debug!("HTTP request failed: {:#}", e);
+            }
+        });
+    }
+}
 
-        // Check if text is empty or too short
-        if text.trim().is_empty() {
-            return Err(anyhow::anyhow!("Message text is empty"));
+async fn respond_http(
+    stream: TcpStream,
+    handler: &(dyn Fn(&str) -> (u16, String) + Send + Sync),
+) -> Result<()> {
+    // The request line and headers are capped, the headers are read but ignored
+    let mut reader = BufReader::new(stream.take(16 * 1024));
+    let read_head = async {
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut header = String::new();
+        while reader.read_line(&mut header).await? > 0 && header.trim_end() != "" {
+            header.clear();
         }
+        io::Result::Ok(request_line)
+    };
+    let request_line = tokio::time::timeout(Duration::from_secs(10), read_head)
+        .await
+        .context("Timed out reading HTTP request")??;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => handler(target.split('?').next().unwrap_or_default()),
+        _ => (405, "Method not allowed\n".to_string()),
+    };
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    let mut stream = reader.into_inner().into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
 
-        let url = format!(
-            "https://api.telegram.org/bot{}/sendMessage",
-            self.telegram_token
-        );
-
-        let client = reqwest::Client::new();
+// Reports readiness and shutdown to systemd for Type=notify units; does nothing when
+// NOTIFY_SOCKET is unset
+#[cfg(unix)]
+fn notify_systemd(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
 
-        // Build form data
-        let mut form_data = vec![("chat_id", self.telegram_chat_id.as_str()), ("text", text)];
+// Re-reads the token file on every SIGHUP. Requests already in flight finish with the
+// old token, later ones use the new one; a failed reload keeps the current token.
+#[cfg(unix)]
+async fn reload_token_on_hangup(path: PathBuf, config: Arc<SessionConfig>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(
+                "Failed to listen for SIGHUP, token rotation disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Err(e) = config.reload_token(&path) {
+            warn!(
+                "Failed to reload bot token, keeping the current one: {:#}",
+                e
+            );
+        }
+    }
+}
 
-        // Add parse_mode if specified
-        if let Some(mode) = parse_mode {
-            form_data.push(("parse_mode", mode));
+// Resolves when the process is asked to stop (SIGINT, or SIGTERM on Unix)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
+    };
 
-        // If message fits in one part, send it directly
-        if text.chars().count() <= MAX_MESSAGE_LENGTH {
-            let response = client
-                .post(&url)
-                .form(&form_data)
-                .send()
-                .await
-                .context("Failed to send request to Telegram")?;
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                return Err(anyhow::anyhow!("Telegram API error: {} - {}", status, body));
-            }
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
 
-            return Ok(());
+// Reads the bot token from --token-file or --token
+fn resolve_token(args: &Args) -> Result<String> {
+    let token = match (&args.token_file, &args.token) {
+        (Some(path), _) => {
+            info!("Reading token from {}", path.display());
+            read_token_file(path)?
         }
+        (None, Some(token)) => token.clone(),
+        (None, None) => {
+            return Err(anyhow::anyhow!(
+                "Either --token or --token-file is required"
+            ))
+        }
+    };
+    // An unset variable in a compose file or unit often ends up as an empty value, which
+    // would otherwise only fail at the first send
+    if token.trim().is_empty() {
+        return Err(anyhow::anyhow!(
+            "The bot token is empty, check --token or TELEGRAM_TOKEN"
+        ));
+    }
+    Ok(token)
+}
 
-        // Split long message into chunks
-        // Try to split at line boundaries first, then at word boundaries
-        let mut chunks = Vec::new();
-        let mut remaining = text;
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = parse_args()?;
+    init_logging(&args.log_level, args.log_format)?;
 
-        while !remaining.is_empty() {
-            let char_count = remaining.chars().count();
+    // Print version and copyright
+    info!("smtp-to-telegram v{}", env!("CARGO_PKG_VERSION"));
+    info!("Copyright (c) {}", COPYRIGHT);
 
-            if char_count <= MAX_MESSAGE_LENGTH {
-                chunks.push(remaining.to_string());
-                break;
-            }
+    if let Some(path) = &args.config {
+        info!("Loaded configuration from {}", path.display());
+    }
 
-            // Find byte position for MAX_MESSAGE_LENGTH characters
-            let max_byte_pos = remaining
-                .char_indices()
-                .nth(MAX_MESSAGE_LENGTH)
-                .map(|(byte_pos, _)| byte_pos)
-                .unwrap_or(remaining.len());
-
-            // Try to find a good split point (prefer line break, then space)
-            // Look back from the limit to find a natural break point (up to 500 bytes)
-            let search_start = max_byte_pos.saturating_sub(500.min(max_byte_pos));
-            let search_end = max_byte_pos;
-
-            let mut split_pos = max_byte_pos;
-
-            // First, try to find a line break
-            if let Some(byte_pos) = remaining[search_start..search_end]
-                .rfind('\n')
-                .map(|pos| search_start + pos + 1)
-            {
-                split_pos = byte_pos;
-            }
-            // If no line break, try to find a space
-            else if let Some(byte_pos) = remaining[search_start..search_end]
-                .rfind(char::is_whitespace)
-                .map(|pos| search_start + pos + 1)
-            {
-                split_pos = byte_pos;
-            }
+    let token = resolve_token(&args)?;
+
+    let api_base_url = args.api_base_url.trim_end_matches('/').to_string();
+    let parsed_url = reqwest::Url::parse(&api_base_url).context(format!(
+        "Invalid Telegram API base URL: {}",
+        args.api_base_url
+    ))?;
+    if !matches!(parsed_url.scheme(), "http" | "https") || parsed_url.cannot_be_a_base() {
+        return Err(anyhow::anyhow!(
+            "Invalid Telegram API base URL: {}, expected an http or https URL",
+            args.api_base_url
+        ));
+    }
+    // A self-hosted Bot API server accepts uploads up to 2000 MB
+    let max_document_size = if api_base_url == DEFAULT_TELEGRAM_API_URL {
+        MAX_DOCUMENT_SIZE
+    } else {
+        MAX_LOCAL_DOCUMENT_SIZE
+    };
+
+    if args.thread_id.is_some() && args.chat_id.is_empty() {
+        return Err(anyhow::anyhow!("--thread-id requires --chat-id"));
+    }
+    // A topic belongs to one chat
+    if args.thread_id.is_some() && args.chat_id.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "--thread-id can't be used with more than one --chat-id"
+        ));
+    }
 
-            // Split at the found position
-            let (chunk, rest) = remaining.split_at(split_pos);
-            chunks.push(chunk.to_string());
-            remaining = rest;
-        }
+    // Mail needs a destination: a default chat or a route
+    if args.chat_id.is_empty() && args.routes.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Either --chat-id or at least one --route is required"
+        ));
+    }
 
-        // Send each chunk
-        for (index, chunk) in chunks.iter().enumerate() {
-            let chunk_text = if chunks.len() > 1 {
-                format!("[{}/{}]\n\n{}", index + 1, chunks.len(), chunk)
-            } else {
-                chunk.clone()
-            };
+    // Clap enforces these pairs for flags and environment, not for config file defaults
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err(anyhow::anyhow!("tls_cert and tls_key must be set together"));
+    }
+    if args.tls_port.is_some() && args.tls_cert.is_none() {
+        return Err(anyhow::anyhow!("tls_port requires tls_cert and tls_key"));
+    }
+    if args.auth_user.is_some() != args.auth_pass.is_some() {
+        return Err(anyhow::anyhow!(
+            "auth_user and auth_pass must be set together"
+        ));
+    }
+    if args.linkify && args.parse_mode != ParseMode::Html {
+        return Err(anyhow::anyhow!("--linkify requires --parse-mode html"));
+    }
 
-            // Ensure chunk is within limits (with prefix)
-            let final_text = if chunk_text.chars().count() > MAX_MESSAGE_LENGTH {
-                // If prefix makes it too long, truncate the chunk
-                let prefix_len = format!("[{}/{}]\n\n", index + 1, chunks.len())
-                    .chars()
-                    .count();
-                let max_chunk_len = MAX_MESSAGE_LENGTH.saturating_sub(prefix_len);
-                let truncated_chunk: String = chunk.chars().take(max_chunk_len).collect();
-                format!("[{}/{}]\n\n{}", index + 1, chunks.len(), truncated_chunk)
-            } else {
-                chunk_text
-            };
+    let hostname = match &args.hostname {
+        Some(hostname) => hostname.clone(),
+        None => system_hostname(),
+    };
+    if hostname.is_empty() || hostname.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(anyhow::anyhow!("Invalid --hostname: '{}'", hostname));
+    }
 
-            // Build form data for chunk
-            let mut chunk_form_data = vec![
-                ("chat_id", self.telegram_chat_id.as_str()),
-                ("text", &final_text),
-            ];
+    // Real newlines are awkward in flags and environment variables
+    let template = match &args.template {
+        Some(template) => template.replace("\\n", "\n"),
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+    for caps in TEMPLATE_PLACEHOLDER_RE.captures_iter(&template) {
+        if !TEMPLATE_PLACEHOLDERS.contains(&&caps[1]) {
+            return Err(anyhow::anyhow!(
+                "Unknown placeholder {} in --template, expected one of {{{}}}",
+                &caps[0],
+                TEMPLATE_PLACEHOLDERS.join("}, {")
+            ));
+        }
+    }
 
-            // Add parse_mode if specified
-            if let Some(mode) = parse_mode {
-                chunk_form_data.push(("parse_mode", mode));
-            }
+    if args.max_message_length < MIN_MAX_MESSAGE_LENGTH {
+        return Err(anyhow::anyhow!(
+            "--max-message-length must be at least {}",
+            MIN_MAX_MESSAGE_LENGTH
+        ));
+    }
 
-            let response = client
-                .post(&url)
-                .form(&chunk_form_data)
-                .send()
-                .await
-                .context(format!(
-                    "Failed to send chunk {}/{} to Telegram",
-                    index + 1,
-                    chunks.len()
-                ))?;
-
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                return Err(anyhow::anyhow!(
-                    "Telegram API error for chunk {}/{}: {} - {}",
-                    index + 1,
-                    chunks.len(),
-                    status,
-                    body
-                ));
-            }
+    if args.max_recipients == 0 {
+        return Err(anyhow::anyhow!("--max-recipients must be at least 1"));
+    }
 
-            // Small delay between messages to avoid rate limiting
-            if index < chunks.len() - 1 {
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            }
-        }
+    if args.max_connections == 0 {
+        return Err(anyhow::anyhow!("--max-connections must be at least 1"));
+    }
 
-        Ok(())
+    if args.io_timeout == 0 {
+        return Err(anyhow::anyhow!("--io-timeout must be at least 1 second"));
     }
 
-    fn convert_html_to_telegram(&self, html: &str) -> String {
-        TELEGRAM_HTML_SANITIZER.clean(html).to_string()
+    if args.max_send_attempts == 0 {
+        return Err(anyhow::anyhow!("--max-send-attempts must be at least 1"));
     }
 
-    fn extract_text_from_email(&self, email_data: &[u8]) -> (String, Option<String>) {
-        // Use mail-parser to parse the email message
-        // mail-parser automatically handles all encodings (base64, quoted-printable, etc.)
-        // and returns text in UTF-8
-        let parser = MessageParser::default();
+    if args.max_telegram_concurrency == 0 {
+        return Err(anyhow::anyhow!(
+            "--max-telegram-concurrency must be at least 1"
+        ));
+    }
 
-        if let Some(msg) = parser.parse(email_data) {
-            // Get Content-Type
-            let content_type = msg.content_type().and_then(|ct| {
-                ct.subtype().map(|subtype| {
-                    let mime_type = format!("{}/{}", ct.ctype(), subtype);
-                    mime_type.to_lowercase()
-                })
-            });
+    if args.flood_limit > 0 && args.flood_window == 0 {
+        return Err(anyhow::anyhow!("--flood-window must be at least 1 second"));
+    }
 
-            if let Some(ref ct) = content_type {
-                println!("Found Content-Type: {}", ct);
-            } else {
-                println!("Content-Type not found in email headers");
-            }
+    if !(args.rate_limit.is_finite() && args.rate_limit >= 0.0) {
+        return Err(anyhow::anyhow!(
+            "--rate-limit must be 0 or a positive number"
+        ));
+    }
+    if !(args.chat_rate_limit.is_finite() && args.chat_rate_limit >= 0.0) {
+        return Err(anyhow::anyhow!(
+            "--chat-rate-limit must be 0 or a positive number"
+        ));
+    }
 
-            // Get subject in UTF-8
-            let subject = if let Some(subj) = msg.subject() {
-                println!("Subject: {}", subj);
-                subj.to_string()
-            } else {
-                println!("Subject not found in email headers");
-                String::new()
-            };
+    // One HTTP client for all sessions, so connections to the Telegram API are pooled
+    let mut http_client = reqwest::Client::builder()
+        .pool_max_idle_per_host(8)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(30));
+    if let Some(proxy) = &args.proxy {
+        let proxy_url =
+            reqwest::Url::parse(proxy).context(format!("Invalid proxy URL: {}", proxy))?;
+        if !matches!(proxy_url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(anyhow::anyhow!(
+                "Unsupported proxy scheme: {}, expected http, https or socks5",
+                proxy_url.scheme()
+            ));
+        }
+        // Credentials in the URL are not printed
+        let mut shown_url = proxy_url.clone();
+        let _ = shown_url.set_username("");
+        let _ = shown_url.set_password(None);
+        info!("Proxy: {}", shown_url);
+        http_client = http_client.proxy(
+            reqwest::Proxy::all(proxy_url).context(format!("Invalid proxy URL: {}", proxy))?,
+        );
+    }
+    let http_client = Arc::new(http_client.build().context("Failed to build HTTP client")?);
+
+    // A wrong token would otherwise only show up when the first email fails to send
+    if args.dry_run {
+        info!("Dry run: messages are logged instead of sent to Telegram");
+    } else if args.skip_startup_check {
+        info!("Skipping the Telegram startup check");
+    } else {
+        let username = check_bot_token(&http_client, &api_base_url, &token).await?;
+        info!("Bot: @{}", username);
+    }
 
-            // Get body - use HTML if Content-Type is text/html, otherwise use text
-            let body = if let Some(ref ct) = content_type {
-                if ct.starts_with("text/html") {
-                    // Use HTML body for HTML content
-                    msg.body_html(0).unwrap_or_default()
-                } else {
-                    // Use text body for other content types
-                    msg.body_text(0).unwrap_or_default()
-                }
-            } else {
-                // If no Content-Type, try text first, then HTML
-                let text_body = msg.body_text(0).unwrap_or_default();
-                if !text_body.is_empty() {
-                    text_body
-                } else {
-                    msg.body_html(0).unwrap_or_default()
+    // Looked up once here; a renamed channel keeps its numeric ID
+    if args.resolve_chat_id && !args.dry_run {
+        let mut resolved: HashMap<String, i64> = HashMap::new();
+        let chat_ids = args
+            .chat_id
+            .iter_mut()
+            .chain(args.routes.iter_mut().map(|route| &mut route.chat_id));
+        for chat_id in chat_ids.filter(|chat_id| chat_id.starts_with('@')) {
+            let id = match resolved.get(chat_id.as_str()) {
+                Some(&id) => id,
+                None => {
+                    let id = resolve_chat_id(&http_client, &api_base_url, &token, chat_id).await?;
+                    info!("Resolved chat {} to {}", chat_id, id);
+                    resolved.insert(chat_id.clone(), id);
+                    id
                 }
             };
+            *chat_id = id.to_string();
+        }
+    }
 
-            if !body.is_empty() {
-                // Clean up extra whitespace from body
-                let cleaned_body = body
-                    .lines()
-                    .map(|line| line.trim())
-                    .filter(|line| !line.is_empty())
-                    .collect::<Vec<_>>()
-                    .join("\n");
-
-                // Combine subject and body
-                let cleaned = if !subject.is_empty() {
-                    format!("Subject: {}\n\n{}", subject, cleaned_body)
-                } else {
-                    cleaned_body
-                };
-
-                return (cleaned, content_type);
-            }
+    // With IPv4 bound separately, `::` must not claim the IPv4 port as well
+    let v6_only = args.bind.iter().any(|ip| ip.is_ipv4());
+    let mut listeners = Vec::new();
+    for ip in &args.bind {
+        let addr = std::net::SocketAddr::new(*ip, args.port);
+        let listener =
+            bind_listener(addr, v6_only).context(format!("Failed to bind to {}", addr))?;
+        info!("SMTP to Telegram server listening on {}", addr);
+        listeners.push((listener, false));
+    }
+    if let Some(tls_port) = args.tls_port {
+        for ip in &args.bind {
+            let addr = std::net::SocketAddr::new(*ip, tls_port);
+            let listener =
+                bind_listener(addr, v6_only).context(format!("Failed to bind to {}", addr))?;
+            info!("SMTPS (implicit TLS) listening on {}", addr);
+            listeners.push((listener, true));
+        }
+    }
+
+    let metrics_listener = match args.metrics_bind {
+        Some(metrics_addr) => {
+            let listener = TcpListener::bind(metrics_addr).await.context(format!(
+                "Failed to bind metrics endpoint to {}",
+                metrics_addr
+            ))?;
+            info!("Metrics available at http://{}/metrics", metrics_addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    let health_listener = match args.health_bind {
+        Some(health_addr) => {
+            let listener = TcpListener::bind(health_addr)
+                .await
+                .context(format!("Failed to bind health endpoint to {}", health_addr))?;
+            info!("Health check available at http://{}/healthz", health_addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    info!("Token: {}", mask_token(&token));
+    if api_base_url != DEFAULT_TELEGRAM_API_URL {
+        info!("Telegram API: {}", api_base_url);
+    }
+    info!("Hostname: {}", hostname);
+    match args.chat_id.as_slice() {
+        [] => info!("Chat ID: not set, only routed recipients are forwarded"),
+        chat_ids => info!("Chat ID: {}", chat_ids.join(", ")),
+    }
+    if let Some(thread_id) = args.thread_id {
+        info!("Thread ID: {}", thread_id);
+    }
+    for cidr in &args.allow_cidrs {
+        info!(
+            "Allowing connections from {}/{}",
+            cidr.network, cidr.prefix_len
+        );
+    }
+    if !args.from_allow.is_empty() {
+        info!("Forwarding mail from: {}", args.from_allow.join(", "));
+    }
+    if !args.from_block.is_empty() {
+        info!("Dropping mail from: {}", args.from_block.join(", "));
+    }
+    if let Some(regex) = &args.subject_regex {
+        info!("Forwarding subjects matching: {}", regex);
+    }
+    for route in &args.routes {
+        match route.thread_id {
+            Some(thread_id) => info!(
+                "Route: {} -> {} (thread {})",
+                route.pattern, route.chat_id, thread_id
+            ),
+            None => info!("Route: {} -> {}", route.pattern, route.chat_id),
+        }
+    }
+
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let acceptor = load_tls_acceptor(cert, key)?;
+            info!("STARTTLS enabled");
+            Some(acceptor)
+        }
+        _ => None,
+    };
+
+    // Everything needing root is done: listeners are bound and the TLS key is loaded.
+    // The spool directory is created afterwards so the new user owns it.
+    if args.user.is_some() || args.group.is_some() {
+        #[cfg(unix)]
+        drop_privileges(args.user.as_deref(), args.group.as_deref())?;
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "--user and --group are only supported on Unix"
+        ));
+    }
+    if let Some(path) = &args.pid_file {
+        write_pid_file(path)?;
+    }
+
+    let spool = match &args.spool_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).context(format!(
+                "Failed to create spool directory {}",
+                dir.display()
+            ))?;
+            info!(
+                "Spooling failed messages to {} (up to {} bytes, {}s)",
+                dir.display(),
+                args.spool_max_size,
+                args.spool_max_age
+            );
+            Some(Spool {
+                dir: dir.clone(),
+                max_age: Duration::from_secs(args.spool_max_age),
+                max_size: args.spool_max_size,
+            })
+        }
+        None => None,
+    };
+
+    let created_topics = match &args.topics_file {
+        Some(path) => {
+            let topics = load_created_topics(path)?;
+            info!(
+                "Loaded {} sender topics from {}",
+                topics.len(),
+                path.display()
+            );
+            topics
+        }
+        None => HashMap::new(),
+    };
+
+    let include_headers = resolve_include_headers(&args.include_headers);
+    if !include_headers.is_empty() {
+        info!("Including headers: {}", include_headers.join(", "));
+    }
 
-            // Fallback: return empty
-            (String::new(), content_type)
+    let auth_credentials = match (&args.auth_user, &args.auth_pass) {
+        (Some(user), Some(pass)) => {
+            info!("SMTP AUTH required for user {}", user);
+            Some((user.clone(), pass.clone()))
+        }
+        _ => None,
+    };
+
+    let config = Arc::new(SessionConfig {
+        telegram_token: RwLock::new(token),
+        telegram_chat_ids: args.chat_id.clone(),
+        thread_id: args.thread_id,
+        api_base_url,
+        max_document_size,
+        routes: args.routes.clone(),
+        sender_topics: args.sender_topics,
+        from_allow: args.from_allow,
+        from_block: args.from_block,
+        subject_regex: args.subject_regex,
+        buttons: args.buttons,
+        auto_create_topics: args.auto_create_topics,
+        created_topics: Mutex::new(created_topics),
+        topics_file: args.topics_file.clone(),
+        include_headers,
+        allow_parse_mode_header: args.allow_parse_mode_header,
+        show_auth_results: args.show_auth_results,
+        no_whitespace_cleanup: args.no_whitespace_cleanup,
+        chunk_header: args.chunk_header,
+        prefer_header_addresses: args.prefer_header_addresses,
+        prefer: if args.prefer_plaintext {
+            PreferredPart::Text
         } else {
-            eprintln!("Failed to parse email message");
-            // Fallback to string conversion
-            let text = String::from_utf8_lossy(email_data).to_string();
-            (text, None)
+            args.prefer
+        },
+        strip_quotes: args.strip_quotes,
+        no_link_preview: args.no_link_preview,
+        silent: args.silent,
+        dry_run: args.dry_run,
+        timezone: args.timezone,
+        show_unsubscribe: args.show_unsubscribe,
+        parse_mode: args.parse_mode,
+        linkify: args.linkify,
+        transforms: args.transforms,
+        max_message_size: args.max_message_size,
+        max_message_length: args.max_message_length,
+        split_lookback: args.split_lookback,
+        long_as_document: args.long_as_document,
+        max_recipients: args.max_recipients,
+        io_timeout: args.io_timeout,
+        greeting_delay: args.greeting_delay,
+        telegram_permits: Semaphore::new(args.max_telegram_concurrency),
+        max_send_attempts: args.max_send_attempts,
+        confirm_delivery: args.confirm_delivery,
+        flood_limit: args.flood_limit,
+        flood_window: Duration::from_secs(args.flood_window),
+        flood_windows: Mutex::new(HashMap::new()),
+        dedupe_ttl: Duration::from_secs(args.dedupe_ttl),
+        seen_messages: Mutex::new(SeenMessages::default()),
+        spool,
+        rate_limit: args.rate_limit,
+        chat_rate_limit: args.chat_rate_limit,
+        chunk_delay: Duration::from_millis(args.chunk_delay_ms),
+        rate_buckets: Mutex::new((
+            TokenBucket::full(args.rate_limit, Instant::now()),
+            HashMap::new(),
+        )),
+        metrics: Metrics::default(),
+        hostname,
+        tls_acceptor,
+        auth_credentials,
+        empty_placeholder: args.forward_empty_as_placeholder.clone(),
+        attach_eml: args.attach_eml,
+        template,
+    });
+
+    #[cfg(unix)]
+    if let Some(path) = args.token_file.clone() {
+        tokio::spawn(reload_token_on_hangup(path, Arc::clone(&config)));
+    }
+
+    let telegram = TelegramClient::new(Arc::clone(&http_client), Arc::clone(&config));
+    if config.spool.is_some() {
+        tokio::spawn(retry_spooled_messages(telegram.clone()));
+    }
+    if config.flood_limit > 0 {
+        tokio::spawn(flush_flood_notices(telegram.clone()));
+    }
+
+    if let Some(listener) = metrics_listener {
+        let config = Arc::clone(&config);
+        tokio::spawn(serve_http(listener, move |path| match path {
+            "/metrics" => (200, config.metrics.render()),
+            _ => (404, "Not found\n".to_string()),
+        }));
+    }
+
+    // The startup check passed or was skipped, later checks only run for the health endpoint
+    let health = Arc::new(Health {
+        accepting: AtomicBool::new(false),
+        telegram_ok: AtomicBool::new(true),
+    });
+    if let Some(listener) = health_listener {
+        if !args.skip_startup_check && !args.dry_run {
+            tokio::spawn(monitor_telegram(
+                Arc::clone(&http_client),
+                Arc::clone(&config),
+                Arc::clone(&health),
+            ));
         }
+        let health = Arc::clone(&health);
+        tokio::spawn(serve_http(listener, move |path| match path {
+            "/healthz" => health.status(),
+            _ => (404, "Not found\n".to_string()),
+        }));
     }
 
-    async fn handle(&mut self) -> Result<()> {
-        // Send greeting
-        self.send_response(Response::new(
-            220,
-            0,
-            0,
-            0,
-            "SMTP to Telegram Service Ready".to_string(),
-        ))
-        .await?;
-
-        let mut mail_from: Option<String> = None;
-        let mut rcpt_to: Option<String> = None;
-        let mut in_data = false;
-
-        loop {
-            // Handle authentication state
-            match self.auth_state {
-                AuthState::LoginWaitingUsername | AuthState::LoginWaitingPassword | AuthState::PlainWaitingData => {
-                    let line_bytes = self.read_line_bytes().await?;
-                    let line = String::from_utf8_lossy(&line_bytes[..line_bytes.len().saturating_sub(2)]);
-                    let line = line.trim();
-                    
-                    match self.auth_state {
-                        AuthState::LoginWaitingUsername => {
-                            // Decode base64 username
-                            let username = if line.is_empty() {
-                                String::new()
-                            } else {
-                                match general_purpose::STANDARD.decode(line) {
-                                    Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
-                                    Err(_) => line.to_string(),
-                                }
-                            };
-                            println!("AUTH LOGIN username: {}", username);
-                            self.send_response(Response::new(334, 0, 0, 0, "UGFzc3dvcmQ6".to_string())) // "Password:" in base64
-                                .await?;
-                            self.auth_state = AuthState::LoginWaitingPassword;
-                            continue;
-                        }
-                        AuthState::LoginWaitingPassword => {
-                            // Decode base64 password
-                            let password = if line.is_empty() {
-                                String::new()
-                            } else {
-                                match general_purpose::STANDARD.decode(line) {
-                                    Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
-                                    Err(_) => line.to_string(),
-                                }
-                            };
-                            println!("AUTH LOGIN password: {}", password);
-                            self.send_response(Response::new(235, 0, 0, 0, "Authentication successful".to_string()))
-                                .await?;
-                            self.auth_state = AuthState::None;
-                            continue;
-                        }
-                        AuthState::PlainWaitingData => {
-                            // PLAIN format: \0username\0password (base64 encoded)
-                            let decoded = if line.is_empty() {
-                                Vec::new()
-                            } else {
-                                match general_purpose::STANDARD.decode(line) {
-                                    Ok(bytes) => bytes,
-                                    Err(_) => {
-                                        self.send_response(Response::new(535, 0, 0, 0, "Authentication failed".to_string()))
-                                            .await?;
-                                        self.auth_state = AuthState::None;
-                                        continue;
-                                    }
-                                }
-                            };
-                            
-                            // Parse PLAIN format: \0username\0password
-                            let parts: Vec<&[u8]> = decoded.split(|&b| b == 0).collect();
-                            if parts.len() >= 3 {
-                                let username = String::from_utf8_lossy(parts[1]).to_string();
-                                let password = String::from_utf8_lossy(parts[2]).to_string();
-                                println!("AUTH PLAIN username: {}", username);
-                                println!("AUTH PLAIN password: {}", password);
-                            } else if parts.len() == 2 {
-                                // Sometimes format is username\0password
-                                let username = String::from_utf8_lossy(parts[0]).to_string();
-                                let password = String::from_utf8_lossy(parts[1]).to_string();
-                                println!("AUTH PLAIN username: {}", username);
-                                println!("AUTH PLAIN password: {}", password);
-                            } else {
-                                println!("AUTH PLAIN raw data: {}", line);
-                            }
-                            
-                            self.send_response(Response::new(235, 0, 0, 0, "Authentication successful".to_string()))
-                                .await?;
-                            self.auth_state = AuthState::None;
-                            continue;
-                        }
-                        _ => {}
-                    }
+    let connection_permits = Arc::new(Semaphore::new(args.max_connections));
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut sessions = JoinSet::new();
+    let mut next_session_id: u64 = 1;
+    let allow_cidrs = Arc::new(args.allow_cidrs.clone());
+
+    // Every listener feeds the same accept loop
+    let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::channel(16);
+    let mut acceptors = JoinSet::new();
+    for (listener, implicit_tls) in listeners {
+        let accepted_tx = accepted_tx.clone();
+        // Connections to the SMTPS port start with the TLS handshake
+        let tls_acceptor = if implicit_tls {
+            config.tls_acceptor.clone()
+        } else {
+            None
+        };
+        acceptors.spawn(async move {
+            loop {
+                let accepted = listener.accept().await;
+                if accepted_tx
+                    .send((accepted, tls_acceptor.clone()))
+                    .await
+                    .is_err()
+                {
+                    break;
                 }
-                _ => {}
             }
-            
-            if in_data {
-                // In DATA mode, read bytes directly to preserve UTF-8 encoding
-                let line_bytes = self.read_line_bytes().await?;
-
-                // Check if this is the end of DATA (single dot on a line)
-                if line_bytes.len() == 3
-                    && line_bytes[0] == b'.'
-                    && line_bytes[1] == b'\r'
-                    && line_bytes[2] == b'\n'
-                {
-                    // End of DATA
-                    in_data = false;
-
-                    // Process the received message - decode as UTF-8
-                    let total_bytes = self.buffer.len();
-                    println!("Received email message: {} bytes", total_bytes);
-
-                    // Use mail-parser which handles all encodings automatically
-                    let (text, content_type) = self.extract_text_from_email(&self.buffer);
-
-                    if !text.is_empty() {
-                        // Determine parse_mode based on Content-Type and convert HTML if needed
-                        let (processed_text, parse_mode) = if let Some(ct) = &content_type {
-                            if ct.starts_with("text/html") {
-                                println!("Converting HTML to Telegram-compatible format");
-                                let converted = self.convert_html_to_telegram(&text);
-                                (converted, Some("HTML"))
-                            } else {
-                                (text, None)
-                            }
-                        } else {
-                            (text, None)
-                        };
-
-                        // Format message for Telegram
-                        let telegram_message =
-                            if let (Some(from), Some(to)) = (&mail_from, &rcpt_to) {
-                                format!("From: {}\nTo: {}\n\n{}", from, to, processed_text)
-                            } else {
-                                processed_text
-                            };
-
-                        if let Some(mode) = parse_mode {
-                            println!(
-                                "Detected Content-Type: {}, using parse_mode: {}",
-                                content_type.as_ref().unwrap(),
-                                mode
-                            );
-                        }
+        });
+    }
+    drop(accepted_tx);
+    health.accepting.store(true, Ordering::Relaxed);
+    #[cfg(unix)]
+    notify_systemd(sd_notify::NotifyState::Ready);
 
-                        let message_bytes = telegram_message.len();
-                        println!(
-                            "Message to send: {} bytes ({} characters)",
-                            message_bytes,
-                            telegram_message.chars().count()
-                        );
-
-                        if let Err(e) = self.send_to_telegram(&telegram_message, parse_mode).await {
-                            eprintln!("Failed to send to Telegram: {}", e);
-                        } else {
-                            println!("Message forwarded to Telegram successfully");
-                        }
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutdown signal received, no longer accepting connections");
+                break;
+            }
+            // Reap finished sessions so the set doesn't grow unbounded
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            Some((accepted, tls_acceptor)) = accepted_rx.recv() => match accepted {
+                Ok((mut stream, addr)) => {
+                    let session_id = next_session_id;
+                    next_session_id += 1;
+                    let span = tracing::info_span!(
+                        "session",
+                        id = session_id,
+                        peer = %addr,
+                        client = tracing::field::Empty
+                    );
+
+                    // Behind a proxy the allowlist is checked once the header has been read
+                    if !args.proxy_protocol && !Cidr::allows(&args.allow_cidrs, addr.ip()) {
+                        span.in_scope(|| {
+                            warn!("Rejecting connection from {}: not in --allow-cidr", addr)
+                        });
+                        sessions.spawn(async move {
+                            let _ = stream.write_all(b"554 Access denied\r\n").await;
+                            let _ = stream.shutdown().await;
+                        });
+                        continue;
                     }
 
-                    self.buffer.clear();
-                    mail_from = None;
-                    rcpt_to = None;
-
-                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
-                        .await?;
-                } else {
-                    // If line starts with "..", remove the first dot (SMTP escaping)
-                    let processed_bytes = if line_bytes.len() >= 3
-                        && line_bytes[0] == b'.'
-                        && line_bytes[1] == b'.'
-                        && line_bytes[line_bytes.len() - 2] == b'\r'
-                        && line_bytes[line_bytes.len() - 1] == b'\n'
-                    {
-                        // Remove the first dot and keep the rest (including \r\n)
-                        line_bytes[1..].to_vec()
-                    } else {
-                        // Keep the line as is (including \r\n)
-                        line_bytes
+                    // Refuse right away instead of queueing, the client will retry later
+                    let Ok(permit) = Arc::clone(&connection_permits).try_acquire_owned() else {
+                        span.in_scope(|| {
+                            warn!(
+                                "Rejecting connection from {}: {} connections already open",
+                                addr, args.max_connections
+                            )
+                        });
+                        sessions.spawn(async move {
+                            let _ = stream.write_all(b"421 Too many connections\r\n").await;
+                            let _ = stream.shutdown().await;
+                        });
+                        continue;
                     };
 
-                    // Continue reading data - store bytes directly
-                    self.buffer.extend_from_slice(&processed_bytes);
-                }
-                continue;
-            }
+                    if !args.proxy_protocol {
+                        span.in_scope(|| info!("New connection from {}", addr));
+                    }
 
-            // Parse SMTP command
-            // Request::parse requires a complete line with \r\n, so we read bytes directly
-            let line_bytes = self.read_line_bytes().await?;
-            let line_str = String::from_utf8_lossy(&line_bytes[..line_bytes.len().saturating_sub(2)]);
-            let line_upper = line_str.trim().to_uppercase();
-            
-            // Check if it's an AUTH command before parsing
-            if line_upper.starts_with("AUTH ") {
-                let parts: Vec<&str> = line_str.trim().split_whitespace().collect();
-                if parts.len() >= 2 {
-                    let mechanism = parts[1].to_uppercase();
-                    let initial_response = if parts.len() >= 3 {
-                        Some(parts[2..].join(" "))
-                    } else {
-                        None
-                    };
-                    
-                    match mechanism.as_str() {
-                        "LOGIN" => {
-                            if let Some(initial) = initial_response {
-                                // Username provided in initial response
-                                let username = match general_purpose::STANDARD.decode(&initial) {
-                                    Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
-                                    Err(_) => initial.clone(),
+                    let telegram = telegram.clone();
+                    let proxy_protocol = args.proxy_protocol;
+                    let allow_cidrs = Arc::clone(&allow_cidrs);
+                    let handshake_timeout = Duration::from_secs(args.io_timeout);
+
+                    sessions.spawn(
+                        async move {
+                            // Released when the session ends
+                            let _permit = permit;
+                            if proxy_protocol {
+                                let header = tokio::time::timeout(
+                                    Duration::from_secs(10),
+                                    read_proxy_header(&mut stream),
+                                )
+                                .await
+                                .unwrap_or_else(|_| {
+                                    Err(anyhow::anyhow!("Timed out waiting for PROXY header"))
+                                });
+                                let client = match header {
+                                    Ok(client) => client.unwrap_or(addr),
+                                    Err(e) => {
+                                        warn!("Dropping connection from {}: {:#}", addr, e);
+                                        return;
+                                    }
                                 };
-                                println!("AUTH LOGIN username: {}", username);
-                                self.send_response(Response::new(334, 0, 0, 0, "UGFzc3dvcmQ6".to_string())) // "Password:" in base64
-                                    .await?;
-                                self.auth_state = AuthState::LoginWaitingPassword;
-                            } else {
-                                // Request username
-                                self.send_response(Response::new(334, 0, 0, 0, "VXNlcm5hbWU6".to_string())) // "Username:" in base64
-                                    .await?;
-                                self.auth_state = AuthState::LoginWaitingUsername;
+                                tracing::Span::current()
+                                    .record("client", tracing::field::display(client));
+                                if !Cidr::allows(&allow_cidrs, client.ip()) {
+                                    warn!(
+                                        "Rejecting connection from {}: not in --allow-cidr",
+                                        client
+                                    );
+                                    let _ = stream.write_all(b"554 Access denied\r\n").await;
+                                    let _ = stream.shutdown().await;
+                                    return;
+                                }
+                                info!("New connection from {} via {}", client, addr);
                             }
-                            continue;
-                        }
-                        "PLAIN" => {
-                            if let Some(initial) = initial_response {
-                                // PLAIN data provided in initial response
-                                let decoded = match general_purpose::STANDARD.decode(&initial) {
-                                    Ok(bytes) => bytes,
-                                    Err(_) => {
-                                        self.send_response(Response::new(535, 0, 0, 0, "Authentication failed".to_string()))
-                                            .await?;
-                                        continue;
+                            let stream = match tls_acceptor {
+                                Some(acceptor) => {
+                                    let handshake = acceptor.accept(stream);
+                                    match tokio::time::timeout(handshake_timeout, handshake).await {
+                                        Ok(Ok(tls)) => SmtpStream::Tls(Box::new(tls)),
+                                        Ok(Err(e)) => {
+                                            warn!("TLS handshake failed: {}", e);
+                                            return;
+                                        }
+                                        Err(_) => {
+                                            warn!("TLS handshake timed out");
+                                            return;
+                                        }
                                     }
-                                };
-                                
-                                // Parse PLAIN format: \0username\0password
-                                let parts: Vec<&[u8]> = decoded.split(|&b| b == 0).collect();
-                                if parts.len() >= 3 {
-                                    let username = String::from_utf8_lossy(parts[1]).to_string();
-                                    let password = String::from_utf8_lossy(parts[2]).to_string();
-                                    println!("AUTH PLAIN username: {}", username);
-                                    println!("AUTH PLAIN password: {}", password);
-                                } else if parts.len() == 2 {
-                                    let username = String::from_utf8_lossy(parts[0]).to_string();
-                                    let password = String::from_utf8_lossy(parts[1]).to_string();
-                                    println!("AUTH PLAIN username: {}", username);
-                                    println!("AUTH PLAIN password: {}", password);
-                                } else {
-                                    println!("AUTH PLAIN raw data: {}", initial);
                                 }
-                                
-                                self.send_response(Response::new(235, 0, 0, 0, "Authentication successful".to_string()))
-                                    .await?;
-                                self.auth_state = AuthState::None;
-                            } else {
-                                // Request PLAIN data
-                                self.send_response(Response::new(334, 0, 0, 0, "".to_string()))
-                                    .await?;
-                                self.auth_state = AuthState::PlainWaitingData;
+                                None => SmtpStream::Plain(stream),
+                            };
+                            let mut session =
+                                SmtpSession::new(stream, session_id, telegram);
+                            if let Err(e) = session.handle().await {
+                                error!("Error handling session: {}", e);
                             }
-                            continue;
                         }
-                        _ => {
-                            self.send_response(Response::new(
-                                504,
-                                0,
-                                0,
-                                0,
-                                format!("Unsupported authentication method: {}", mechanism),
-                            ))
-                            .await?;
-                            continue;
-                        }
-                    }
-                }
-            }
-            
-            let mut iter = line_bytes.iter();
-            let request = Request::parse(&mut iter)
-                .map_err(|e| anyhow::anyhow!("Failed to parse SMTP request: {:?}", e))?;
-
-            match request {
-                Request::Helo { host } => {
-                    self.send_response(Response::new(
-                        250,
-                        0,
-                        0,
-                        0,
-                        format!("Hello {}", host.into_owned()),
-                    ))
-                    .await?;
-                }
-                Request::Ehlo { host } => {
-                    // EHLO should advertise AUTH support
-                    // Send multi-line response in correct SMTP format
-                    let host_str = host.into_owned();
-                    // Format: continuation lines with dash (250-), last line without dash (250 )
-                    let response = format!("250-Hello {}\r\n250 AUTH LOGIN PLAIN\r\n", host_str);
-                    println!("Sending EHLO response: {:?}", response);
-                    self.stream
-                        .write_all(response.as_bytes())
-                        .await
-                        .context("Failed to write EHLO response")?;
-                    self.stream
-                        .flush()
-                        .await
-                        .context("Failed to flush EHLO response")?;
-                    println!("EHLO response sent and flushed");
-                }
-                Request::Lhlo { host } => {
-                    self.send_response(Response::new(
-                        250,
-                        0,
-                        0,
-                        0,
-                        format!("Hello {}", host.into_owned()),
-                    ))
-                    .await?;
-                }
-                Request::Mail { from } => {
-                    mail_from = Some(from.address.into_owned());
-                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
-                        .await?;
-                }
-                Request::Rcpt { to } => {
-                    rcpt_to = Some(to.address.into_owned());
-                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
-                        .await?;
-                }
-                Request::Data => {
-                    if mail_from.is_none() || rcpt_to.is_none() {
-                        self.send_response(Response::new(
-                            503,
-                            0,
-                            0,
-                            0,
-                            "Need MAIL and RCPT first".to_string(),
-                        ))
-                        .await?;
-                        continue;
-                    }
-                    in_data = true;
-                    self.buffer.clear();
-                    self.send_response(Response::new(
-                        354,
-                        0,
-                        0,
-                        0,
-                        "End data with <CR><LF>.<CR><LF>".to_string(),
-                    ))
-                    .await?;
-                }
-                Request::Rset => {
-                    mail_from = None;
-                    rcpt_to = None;
-                    self.buffer.clear();
-                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
-                        .await?;
+                        .instrument(span),
+                    );
                 }
-                Request::Quit => {
-                    self.send_response(Response::new(221, 0, 0, 0, "Bye".to_string()))
-                        .await?;
-                    break;
-                }
-                Request::Noop { .. } => {
-                    self.send_response(Response::new(250, 0, 0, 0, "OK".to_string()))
-                        .await?;
-                }
-                Request::Vrfy { .. } => {
-                    self.send_response(Response::new(
-                        502,
-                        0,
-                        0,
-                        0,
-                        "Command not implemented".to_string(),
-                    ))
-                    .await?;
-                }
-                Request::Expn { .. } => {
-                    self.send_response(Response::new(
-                        502,
-                        0,
-                        0,
-                        0,
-                        "Command not implemented".to_string(),
-                    ))
-                    .await?;
-                }
-                Request::Help { .. } => {
-                    self.send_response(Response::new(214, 0, 0, 0, "Help text".to_string()))
-                        .await?;
-                }
-                Request::StartTls => {
-                    self.send_response(Response::new(
-                        502,
-                        0,
-                        0,
-                        0,
-                        "TLS not supported".to_string(),
-                    ))
-                    .await?;
-                }
-                Request::Auth { .. } => {
-                    // AUTH command is handled above before parsing
-                    // This should not be reached, but handle it anyway
-                    self.send_response(Response::new(
-                        502,
-                        0,
-                        0,
-                        0,
-                        "Auth not supported".to_string(),
-                    ))
-                    .await?;
-                }
-                Request::Bdat { .. }
-                | Request::Burl { .. }
-                | Request::Etrn { .. }
-                | Request::Atrn { .. } => {
-                    self.send_response(Response::new(
-                        502,
-                        0,
-                        0,
-                        0,
-                        "Command not implemented".to_string(),
-                    ))
-                    .await?;
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
                 }
-            }
+            },
+        }
+    }
+    health.accepting.store(false, Ordering::Relaxed);
+    #[cfg(unix)]
+    notify_systemd(sd_notify::NotifyState::Stopping);
+    // Aborting the acceptors closes the listening sockets
+    acceptors.shutdown().await;
+
+    // Let in-flight sessions finish forwarding their messages
+    if !sessions.is_empty() {
+        info!(
+            "Waiting up to {}s for {} active session(s) to finish",
+            args.shutdown_timeout,
+            sessions.len()
+        );
+        let drain = async { while sessions.join_next().await.is_some() {} };
+        let grace = Duration::from_secs(args.shutdown_timeout);
+        if tokio::time::timeout(grace, drain).await.is_err() {
+            warn!(
+                "Shutdown timeout reached, aborting {} session(s)",
+                sessions.len()
+            );
+            sessions.shutdown().await;
         }
-
-        Ok(())
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+    if let Some(path) = &args.pid_file {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove PID file {}: {}", path.display(), e);
+        }
+    }
 
-    // Print version and copyright
-    println!("smtp-to-telegram v{}", env!("CARGO_PKG_VERSION"));
-    println!("Copyright (c) {}", COPYRIGHT);
-    println!();
+    info!("Shutdown complete");
+    Ok(())
+}
 
-    // Validate bind address
-    args.bind
-        .parse::<std::net::IpAddr>()
-        .context(format!("Invalid bind address: {}", args.bind))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let addr = format!("{}:{}", args.bind, args.port);
-    let listener = TcpListener::bind(&addr)
-        .await
-        .context(format!("Failed to bind to {}", addr))?;
+    fn parse(flags: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("smtp-to-telegram").chain(flags.iter().copied()))
+    }
 
-    println!("SMTP to Telegram server listening on {}", addr);
-    println!("Token: {}...", &args.token[..args.token.len().min(10)]);
-    println!("Chat ID: {}", args.chat_id);
+    #[test]
+    fn empty_token_fails_at_startup() {
+        for token in ["", "   "] {
+            let args = parse(&["--token", token, "--chat-id", "42"]).unwrap();
+            let error = resolve_token(&args).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "The bot token is empty, check --token or TELEGRAM_TOKEN"
+            );
+        }
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                println!("New connection from {}", addr);
+        let path =
+            std::env::temp_dir().join(format!("smtp-to-telegram-empty-{}", std::process::id()));
+        std::fs::write(&path, "\n").unwrap();
+        let args = parse(&["--token-file", path.to_str().unwrap(), "--chat-id", "42"]).unwrap();
+        let error = resolve_token(&args).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().ends_with("is empty"), "{}", error);
 
-                let telegram_token = args.token.clone();
-                let telegram_chat_id = args.chat_id.clone();
+        let args = parse(&["--token", "123:abc", "--chat-id", "42"]).unwrap();
+        assert_eq!(resolve_token(&args).unwrap(), "123:abc");
+    }
 
-                tokio::spawn(async move {
-                    let mut session = SmtpSession::new(stream, telegram_token, telegram_chat_id);
-                    if let Err(e) = session.handle().await {
-                        eprintln!("Error handling session: {}", e);
-                    }
-                });
-            }
-            Err(e) => {
-                eprintln!("Failed to accept connection: {}", e);
-            }
+    #[test]
+    fn empty_chat_id_fails_at_startup() {
+        for chat_id in ["", "  "] {
+            let error = parse(&["--token", "123:abc", "--chat-id", chat_id]).unwrap_err();
+            assert!(
+                error.to_string().contains("the chat ID is empty"),
+                "{}",
+                error
+            );
         }
     }
 }